@@ -0,0 +1,113 @@
+//! Compares how fast the controller counts the messages that return to it,
+//! before and after it ran on stable Rust. Before, the counters were
+//! `RefCell<Rc<u64>>` written through `Rc::get_mut_unchecked`, and now they
+//! are `Rc<Cell<u64>>`.
+//!
+//! Run with `cargo bench --bench counters`.
+
+use std::{
+    cell::{Cell, RefCell},
+    hint::black_box,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// The number of returned messages counted per round
+const MESSAGES: u64 = 100_000_000;
+const ROUNDS: usize = 10;
+
+/// The counters of the controller before
+struct Before {
+    return_counter: RefCell<Rc<u64>>,
+    processed_packets: RefCell<Rc<u64>>,
+}
+
+impl Before {
+    /// Writes through the `Rc` like `Rc::get_mut_unchecked`, which needs
+    /// nightly
+    fn increment(counter: &RefCell<Rc<u64>>) {
+        let counter = counter.borrow_mut();
+        unsafe { *(Rc::as_ptr(&counter) as *mut u64) += 1 };
+    }
+
+    fn count(&self, processed: bool) {
+        if processed {
+            Self::increment(&self.processed_packets);
+        }
+        Self::increment(&self.return_counter);
+    }
+
+    fn returned(&self) -> u64 {
+        **self.return_counter.borrow()
+    }
+}
+
+/// The counters of the controller now
+struct After {
+    returned: Rc<Cell<u64>>,
+    processed_packets: Rc<Cell<u64>>,
+}
+
+impl After {
+    fn count(&self, processed: bool) {
+        if processed {
+            self.processed_packets.set(self.processed_packets.get() + 1);
+        }
+        self.returned.set(self.returned.get() + 1);
+    }
+
+    fn returned(&self) -> u64 {
+        self.returned.get()
+    }
+}
+
+/// Counts `MESSAGES` returned messages with `count`, of which all but one in
+/// eight were processed, and checks the count like the controller does.
+fn round(count: impl Fn(bool), returned: impl Fn() -> u64) -> Duration {
+    let start = Instant::now();
+    for message in 0..MESSAGES {
+        count(black_box(message) % 8 != 0);
+    }
+    assert_eq!(black_box(returned()), MESSAGES);
+    start.elapsed()
+}
+
+/// Prints the time per message of the fastest and of the mean round
+fn report(name: &str, rounds: &[Duration]) {
+    let per_message =
+        |duration: Duration| duration.as_nanos() as f64 / MESSAGES as f64;
+    let min = rounds.iter().min().unwrap();
+    let mean = rounds.iter().sum::<Duration>() / rounds.len() as u32;
+    println!(
+        "{name}\tmin {:.3} ns\tmean {:.3} ns per message",
+        per_message(*min),
+        per_message(mean)
+    );
+}
+
+fn main() {
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    // In turns, so that both see the same machine
+    for _ in 0..ROUNDS {
+        let counters = Before {
+            return_counter: RefCell::new(Rc::new(0)),
+            processed_packets: RefCell::new(Rc::new(0)),
+        };
+        before.push(round(
+            |processed| counters.count(processed),
+            || counters.returned(),
+        ));
+
+        let counters = After {
+            returned: Rc::new(Cell::new(0)),
+            processed_packets: Rc::new(Cell::new(0)),
+        };
+        after.push(round(
+            |processed| counters.count(processed),
+            || counters.returned(),
+        ));
+    }
+    report("before", &before);
+    report("after", &after);
+}
@@ -0,0 +1,52 @@
+#!/bin/bash
+
+# Compares the throughput of an application before and after a change, by
+# building it at the git revision $1 and in the working tree, and running both
+# $4 times in turns on the worker cores $3.
+#
+# Example: ./compare.sh HEAD~1 revgen 9,11,13 10
+#
+# Revisions that still need a nightly toolchain are built with
+# BEFORE_TOOLCHAIN, which is nightly by default. The revisions from before
+# the crate built on stable no longer build on current nightly, so the
+# counters they used are compared by 'cargo bench --bench counters' instead.
+
+before=$1
+app=$2
+workers=$3
+runs=${4:-5}
+toolchain=${BEFORE_TOOLCHAIN:-nightly}
+
+# The crate is in the rpppp directory of the repository, which is where the
+# working tree is built
+cd "$(dirname "$0")" || exit 1
+
+mkdir -p dat
+rm -f dat/compare_before.txt dat/compare_after.txt
+
+worktree=$(mktemp -d)
+git worktree add --detach $worktree $before || exit 1
+# Also removed when a build fails
+trap "git worktree remove --force $worktree" EXIT
+(cd $worktree/rpppp && cargo +$toolchain b --bin $app --release) || exit 1
+cargo b --bin $app --release || exit 1
+
+# Prints the packets per microsecond of a run without latency measurements
+function throughput ()
+{
+    $1 0 $workers | sed '1,/^# AVG/d' | head -1 | cut -f2
+}
+
+for ((i=0; i<$runs; i++ ));
+do
+    throughput $worktree/rpppp/target/release/$app >> dat/compare_before.txt
+    throughput ./target/release/$app >> dat/compare_after.txt
+done
+
+for version in before after
+do
+    awk -v version=$version '{ sum += $1; if (min == "" || $1 < min) min = $1;
+        if ($1 > max) max = $1 }
+        END { printf "%s\tmean %.4f\tmin %.4f\tmax %.4f Mpps\n",
+            version, sum / NR, min, max }' dat/compare_$version.txt
+done
@@ -0,0 +1,148 @@
+use std::cell::Cell;
+
+use glommio::sync::Semaphore;
+
+/// How many messages can be in the pipeline at once, and what happens to new
+/// messages when it is full, like the new event threshold of DPDK eventdev.
+/// A message takes a credit when it is injected, and every copy of a message
+/// that fans out takes one more. The credits are given back when the messages
+/// leave the pipeline.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Admission {
+    /// There is no limit, other than the sizes of the channels.
+    #[default]
+    Unlimited,
+    /// New messages wait until there is a free credit, which holds back the
+    /// generator. Must be at least one.
+    Delay(usize),
+    /// New messages are discarded if there is no free credit. Must be at
+    /// least one.
+    Reject(usize),
+}
+
+impl Admission {
+    /// The largest number of messages in the pipeline, if there is a limit.
+    pub fn limit(&self) -> Option<usize> {
+        match self {
+            Admission::Unlimited => None,
+            Admission::Delay(limit) | Admission::Reject(limit) => Some(*limit),
+        }
+    }
+}
+
+/// The free credits of the pipeline, kept by the controller, which is where
+/// messages enter and leave the pipeline.
+pub(crate) struct Credits {
+    admission: Admission,
+    available: Semaphore,
+    // Credits taken by copies when none were free, which are paid back before
+    // any credit is freed
+    debt: Cell<u64>,
+    rejected: Cell<u64>,
+    delayed: Cell<u64>,
+}
+
+impl Credits {
+    pub(crate) fn new(admission: Admission) -> Self {
+        Credits {
+            admission,
+            available: Semaphore::new(admission.limit().unwrap_or(0) as u64),
+            debt: Cell::new(0),
+            rejected: Cell::new(0),
+            delayed: Cell::new(0),
+        }
+    }
+
+    /// Takes a credit for a new message. Returns false if the message is
+    /// rejected, and otherwise waits for a free credit if needed.
+    pub(crate) async fn admit(&self) -> bool {
+        if self.admission == Admission::Unlimited
+            || self.available.try_acquire(1).unwrap()
+        {
+            return true;
+        }
+        match self.admission {
+            Admission::Reject(_) => {
+                self.rejected.set(self.rejected.get() + 1);
+                false
+            }
+            _ => {
+                self.delayed.set(self.delayed.get() + 1);
+                self.available.acquire(1).await.unwrap();
+                true
+            }
+        }
+    }
+
+    /// Checks if a new message would have to wait for a credit, or be
+    /// rejected.
+    pub(crate) fn exhausted(&self) -> bool {
+        self.admission != Admission::Unlimited
+            && self.available.available() == 0
+    }
+
+    /// Takes credits for `copies` new copies of a message. The copies are
+    /// never held back, so they go into debt if there are no free credits.
+    pub(crate) fn take(&self, copies: u64) {
+        if self.admission == Admission::Unlimited {
+            return;
+        }
+        for _ in 0..copies {
+            if !self.available.try_acquire(1).unwrap() {
+                self.debt.set(self.debt.get() + 1);
+            }
+        }
+    }
+
+    /// Gives back the credit of a message that has left the pipeline.
+    pub(crate) fn release(&self) {
+        if self.admission == Admission::Unlimited {
+            return;
+        }
+        match self.debt.get() {
+            0 => self.available.signal(1),
+            debt => self.debt.set(debt - 1),
+        }
+    }
+
+    /// Number of new messages that were discarded for lack of credits.
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.get()
+    }
+
+    /// Number of new messages that had to wait for a credit.
+    pub(crate) fn delayed(&self) -> u64 {
+        self.delayed.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glommio::LocalExecutor;
+
+    #[test]
+    fn test_credits() {
+        LocalExecutor::default().run(async {
+            let credits = Credits::new(Admission::Reject(2));
+            assert!(credits.admit().await);
+            assert!(credits.admit().await);
+            assert!(credits.exhausted());
+            assert!(!credits.admit().await);
+            // The copy goes into debt, so two must leave before there is room
+            credits.take(1);
+            credits.release();
+            assert!(!credits.admit().await);
+            credits.release();
+            assert!(credits.admit().await);
+            assert_eq!(credits.rejected(), 2);
+            assert_eq!(credits.delayed(), 0);
+
+            let credits = Credits::new(Admission::Delay(1));
+            assert!(credits.admit().await);
+            credits.release();
+            assert!(credits.admit().await);
+            assert_eq!(credits.delayed(), 0);
+        });
+    }
+}
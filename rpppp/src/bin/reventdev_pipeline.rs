@@ -1,95 +1,187 @@
 use glommio::{channels::shared_channel, LocalExecutorBuilder, Placement};
 use rand::distributions::{Distribution, Uniform};
-use rpppp::{histogram::Histogram, tsc, types::PIPELINE_SIZE};
+use rpppp::{
+    admission::Admission,
+    context::StageContext,
+    dispatch::DispatchPolicy,
+    limit::{CancellationToken, RunLimit},
+    pipeline::{Pipeline, Stage, StageDescriptor, StageOutcome},
+    pool::MsgPool,
+    tsc,
+    types::{ChannelElement, Msg, QueueType},
+};
 use std::{
     env,
+    fmt::Debug,
+    str::FromStr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 const TEST_DURATION: Duration = Duration::from_secs(60);
 const GENERATOR_CORE: u16 = 7;
 const CONTROLLER_CORE: u16 = 5;
-
-const HISTOGRAM_MAX_LATENCY: usize = 100_000;
+const QUEUE_TYPE: QueueType = QueueType::Parallel;
+const DISPATCH_POLICY: DispatchPolicy = DispatchPolicy::RoundRobin;
+const WORK_STEALING: bool = false;
+const ADMISSION: Admission = Admission::Unlimited;
+const DEFAULT_BURST_SIZE: usize = 1;
+const DEFAULT_POOL_SIZE: usize = 0;
+const DEFAULT_NUM_FLOWS: u64 = 1;
+const NUM_STAGES: usize = 3;
 
 const TARGET_CYCLES: u64 = 1000;
 
-static mut CYCLES_TO_BURN: u64 = 0;
-
-static mut HISTOGRAMS: Vec<Vec<Histogram<HISTOGRAM_MAX_LATENCY>>> = Vec::new();
-static mut LATENCY_MEASUREMENT_TYPE: LatencyMeasurement =
-    LatencyMeasurement::None;
-
-#[derive(Clone)]
+#[derive(Clone, Default)]
 struct DataStruct {
     _data: f32,
 }
 
+#[derive(Clone, Copy)]
 enum LatencyMeasurement {
     None,
     Total,
     Switching,
 }
 
-const PROCESS_PIPELINE: [rpppp::types::PipelineElement<DataStruct>;
-    rpppp::types::PIPELINE_SIZE] = [
-    Some(burn_cycles),
-    Some(burn_cycles),
-    Some(burn_cycles),
-    None,
-    None,
-];
+/// Burns cycles and measures the latency. The latencies are recorded in the
+/// histogram "latency" of the stage.
+#[derive(Clone)]
+struct BurnCycles {
+    cycles: u64,
+    latency_measurement: LatencyMeasurement,
+}
 
-fn burn_cycles(
-    _input: &mut Box<rpppp::types::Msg<DataStruct>>,
-    _core_id: usize,
-) {
-    let msg = _input.as_mut();
-    unsafe {
-        if let LatencyMeasurement::Switching = LATENCY_MEASUREMENT_TYPE {
-            HISTOGRAMS[_core_id][msg.pipeline_index]
-                .add_value(msg.timestamp.elapsed().as_micros() as usize);
+impl Stage<DataStruct> for BurnCycles {
+    fn process(
+        &mut self,
+        input: &mut ChannelElement<DataStruct>,
+        ctx: &mut StageContext,
+    ) -> StageOutcome {
+        let msg = input.as_mut();
+        match self.latency_measurement {
+            LatencyMeasurement::Switching => {
+                ctx.record(
+                    "latency",
+                    msg.timestamp.elapsed().as_micros() as usize,
+                );
+                tsc::burn(self.cycles);
+                msg.timestamp = ctx.now();
+            }
+            LatencyMeasurement::Total => {
+                tsc::burn(self.cycles);
+                ctx.record(
+                    "latency",
+                    msg.timestamp.elapsed().as_micros() as usize,
+                );
+            }
+            LatencyMeasurement::None => {
+                tsc::burn(self.cycles);
+            }
         }
+        StageOutcome::Continue
     }
-    tsc::burn(unsafe { CYCLES_TO_BURN });
-
-    unsafe {
-        if let LatencyMeasurement::Switching = LATENCY_MEASUREMENT_TYPE {
-            _input.as_mut().timestamp = Instant::now();
-        } else if let LatencyMeasurement::Total = LATENCY_MEASUREMENT_TYPE {
-            if msg.pipeline_index < PIPELINE_SIZE
-                || msg.pipeline[msg.pipeline_index].is_none()
-            {
-                HISTOGRAMS[_core_id][0]
-                    .add_value(msg.timestamp.elapsed().as_micros() as usize);
-            }
+}
+
+/// The workload, from the arguments after the number of packets: the burst
+/// size, the size of the message pool and the number of flows. Each defaults
+/// to the workload of the original measurements, without bursts, without a
+/// pool and with one flow. A pool size of zero means no pool.
+#[derive(Clone, Copy)]
+struct Workload {
+    burst_size: usize,
+    pool_size: usize,
+    num_flows: u64,
+}
+
+impl Workload {
+    fn from_args(args: &[String]) -> Self {
+        Workload {
+            burst_size: arg(args, 4, DEFAULT_BURST_SIZE),
+            pool_size: arg(args, 5, DEFAULT_POOL_SIZE),
+            num_flows: arg(args, 6, DEFAULT_NUM_FLOWS),
         }
     }
 }
 
+/// The argument at `index`, or `default` if it was not given
+fn arg<T: FromStr>(args: &[String], index: usize, default: T) -> T
+where
+    T::Err: Debug,
+{
+    args.get(index).map_or(default, |arg| arg.parse().unwrap())
+}
+
+/// Creates the pipeline that is run on every message
+fn process_pipeline(
+    cycles_to_burn: u64,
+    latency_measurement: LatencyMeasurement,
+    workload: Workload,
+) -> Arc<Pipeline<DataStruct>> {
+    let mut builder = Pipeline::builder();
+    for _ in 0..NUM_STAGES {
+        builder = builder.stage(StageDescriptor::from_stage(
+            QUEUE_TYPE,
+            BurnCycles {
+                cycles: cycles_to_burn,
+                latency_measurement,
+            },
+        ));
+    }
+    let builder = builder
+        .work_stealing(WORK_STEALING)
+        .admission(ADMISSION)
+        .burst_size(workload.burst_size);
+    match workload.pool_size {
+        0 => builder,
+        size => builder.message_pool(MsgPool::new(size)),
+    }
+    .build()
+    .unwrap()
+}
+
 async fn generate_traffic(
-    task_sender: shared_channel::SharedSender<
-        Box<rpppp::types::Msg<DataStruct>>,
-    >,
-    stop_time: Instant,
+    task_sender: shared_channel::SharedSender<ChannelElement<DataStruct>>,
+    limit: RunLimit,
+    pool: Option<MsgPool<DataStruct>>,
+    num_flows: u64,
 ) {
     let task_sender = task_sender.connect().await;
+    let mut pool = pool.map(|pool| pool.local());
 
     let mut rng = rand::thread_rng();
     let data_distribution = Uniform::from(0_f32..=10_000_f32);
+    let flow_distribution = Uniform::from(0..num_flows);
+
+    let mut sent = 0;
+    while !limit.is_reached(sent) {
+        let data = DataStruct {
+            _data: data_distribution.sample(&mut rng),
+        };
+        let flow_id = flow_distribution.sample(&mut rng);
+        let msg = match pool.as_mut() {
+            Some(pool) => pool.get(data, flow_id),
+            None => Some(Box::new(Msg::new(data, flow_id))),
+        };
+        match msg {
+            Some(msg) => {
+                task_sender.send(msg).await.unwrap();
+                sent += 1;
+            }
+            // Wait for messages to return to the pool
+            None => glommio::executor().yield_now().await,
+        }
+    }
+}
 
-    while stop_time > Instant::now() {
-        task_sender
-            .send(Box::new(rpppp::types::Msg {
-                data: DataStruct {
-                    _data: data_distribution.sample(&mut rng),
-                },
-                pipeline: &PROCESS_PIPELINE,
-                pipeline_index: 0,
-                timestamp: Instant::now(),
-            }))
-            .await
-            .unwrap();
+/// The run limit given by the number of packets to inject, like `-n` of the
+/// C pipeline. Zero runs until Ctrl-C, and without it the run lasts for
+/// [`TEST_DURATION`].
+fn run_limit(num_packets: Option<String>) -> RunLimit {
+    match num_packets.map(|n| n.parse::<u64>().unwrap()) {
+        None => RunLimit::after(TEST_DURATION),
+        Some(0) => RunLimit::Until(CancellationToken::on_sigint().unwrap()),
+        Some(n) => RunLimit::PacketCount(n),
     }
 }
 
@@ -97,53 +189,49 @@ fn main() {
     let starting_time = Instant::now();
     let args: Vec<String> = env::args().collect();
 
-    unsafe {
-        LATENCY_MEASUREMENT_TYPE = match &args[1][..] {
-            "1" => LatencyMeasurement::Total,
-            "2" => LatencyMeasurement::Switching,
-            _ => LatencyMeasurement::None,
-        };
-    }
+    let latency_measurement = match &args[1][..] {
+        "1" => LatencyMeasurement::Total,
+        "2" => LatencyMeasurement::Switching,
+        _ => LatencyMeasurement::None,
+    };
 
     // Ensure that no interrupts during calibration. They only happen on core 0
-    LocalExecutorBuilder::new(Placement::Fixed(GENERATOR_CORE as usize))
-        .name("calibrator")
-        .spawn(move || async move {
-            unsafe {
-                CYCLES_TO_BURN = rpppp::tsc::calibrate(&[TARGET_CYCLES])[0]
-            };
-        })
-        .unwrap()
-        .join()
-        .unwrap();
+    let cycles_to_burn = LocalExecutorBuilder::new(Placement::Fixed(
+        GENERATOR_CORE as usize,
+    ))
+    .name("calibrator")
+    .spawn(move || async move { rpppp::tsc::calibrate(&[TARGET_CYCLES])[0] })
+    .unwrap()
+    .join()
+    .unwrap();
 
     let worker_cores: Vec<_> = args[2]
         .split(',')
         .map(|x| x.parse::<u16>().unwrap())
         .collect();
     let num_workers = worker_cores.len();
-    let num_cores = num_workers + 2; // Generator and scheduler
 
-    let num_histograms = num_workers;
-    let num_stages = PROCESS_PIPELINE.iter().filter(|p| p.is_some()).count();
-
-    unsafe {
-        HISTOGRAMS.clear();
-        HISTOGRAMS.resize_with(num_histograms, Default::default);
-        for h in HISTOGRAMS.iter_mut() {
-            h.resize_with(num_stages, Histogram::new);
-        }
-    }
+    let workload = Workload::from_args(&args);
+    let pipeline =
+        process_pipeline(cycles_to_burn, latency_measurement, workload);
+    let num_stages = pipeline.len();
+    let pool = pipeline.message_pool().cloned();
 
     println!("Using worker cores: {:?}", worker_cores);
 
-    let (run_duration, packets_processed) = rpppp::core::start_sw(
+    let report = rpppp::core::start_sw(
         worker_cores,
         GENERATOR_CORE,
         CONTROLLER_CORE,
-        generate_traffic,
-        Instant::now() + TEST_DURATION,
-    );
+        pipeline,
+        DISPATCH_POLICY,
+        move |task_sender, limit| {
+            generate_traffic(task_sender, limit, pool, workload.num_flows)
+        },
+        run_limit(args.get(3).cloned()),
+    )
+    .unwrap();
+    let run_duration = report.run_duration;
 
     let s = format!(
         "Run duration {:.2}\tending time {:.2}\tdiff {:.2}",
@@ -154,51 +242,60 @@ fn main() {
     eprintln!("\x1b[93m{s}\x1b[0m");
     println!("{s}");
 
-    match unsafe { &LATENCY_MEASUREMENT_TYPE } {
+    match latency_measurement {
         LatencyMeasurement::None => {
-            let done_work =
-                packets_processed * TARGET_CYCLES * num_stages as u64;
-
-            let ideal_work = ((num_cores as f64)
-                * run_duration.as_secs_f64()
-                * (tsc::get_tsc_hz() as f64))
-                as usize;
-
             println!(
                 "# AVG\n{}\t{}\t{}\t{}",
                 num_workers,
-                packets_processed as f64 / run_duration.as_micros() as f64,
-                done_work,
-                ideal_work
+                report.throughput_mpps(),
+                report.done_work(TARGET_CYCLES * num_stages as u64),
+                report.ideal_work(tsc::get_tsc_hz())
             );
         }
         LatencyMeasurement::Total => {
             println!("# TL");
-            let mut total_hist: Histogram<HISTOGRAM_MAX_LATENCY> =
-                Histogram::new();
-            unsafe {
-                HISTOGRAMS
-                    .iter()
-                    .for_each(|hist| total_hist.add_data_from(&hist[0]));
-            }
-
-            total_hist.print(false);
+            report.histogram("latency").unwrap_or_default().print(false);
             println!();
         }
         LatencyMeasurement::Switching => {
             for stage in 0..num_stages {
                 println!("# TSL-{stage}");
-                let mut total_hist: Histogram<HISTOGRAM_MAX_LATENCY> =
-                    Histogram::new();
-                unsafe {
-                    HISTOGRAMS.iter().for_each(|hist| {
-                        total_hist.add_data_from(&hist[stage])
-                    });
-                }
-
+                let mut total_hist = report
+                    .stats
+                    .histogram(stage, "latency")
+                    .cloned()
+                    .unwrap_or_default();
                 total_hist.print(false);
                 println!();
             }
         }
     }
+
+    println!("# UTIL");
+    for utilisation in report.utilisation() {
+        println!("{utilisation:.4}");
+    }
+
+    println!("# STOLEN");
+    for worker in &report.workers {
+        println!("{}", worker.stolen);
+    }
+
+    if let Some(pool) = report.pool {
+        println!("# POOL");
+        println!("size {}\texhausted {}", pool.size, pool.exhausted);
+    }
+
+    println!("# ADMISSION");
+    println!(
+        "rejected {}\tdelayed {}",
+        report.rejected_injections, report.delayed_injections
+    );
+
+    println!("# DRAIN");
+    println!(
+        "duration {:.4}\tabandoned {}",
+        report.drain_duration.as_secs_f64(),
+        report.abandoned_packets
+    );
 }
@@ -1,29 +1,38 @@
 use glommio::{LocalExecutorBuilder, Placement};
 use rand::distributions::{Distribution, Uniform};
-use rpppp::core::ShardReturnRequest;
-use rpppp::histogram::Histogram;
+use rpppp::admission::Admission;
+use rpppp::context::StageContext;
+use rpppp::core::Injector;
+use rpppp::dispatch::DispatchPolicy;
+use rpppp::limit::{CancellationToken, RunLimit};
+use rpppp::pipeline::{Pipeline, Stage, StageDescriptor, StageOutcome};
+use rpppp::pool::MsgPool;
 use rpppp::tsc::{self, get_tsc_hz};
+use rpppp::types::{ChannelElement, QueueType, RunReport};
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{env, time::Instant};
 
 const TEST_DURATION: Duration = Duration::from_secs(60);
 const GENERATOR_CORE: u16 = 7;
-
-const HISTOGRAM_MAX_LATENCY: usize = 100_000;
+const QUEUE_TYPE: QueueType = QueueType::Parallel;
+const DISPATCH_POLICY: DispatchPolicy = DispatchPolicy::RoundRobin;
+const WORK_STEALING: bool = false;
+const ADMISSION: Admission = Admission::Unlimited;
+const DEFAULT_BURST_SIZE: usize = 1;
+const DEFAULT_POOL_SIZE: usize = 0;
+const DEFAULT_NUM_FLOWS: u64 = 1;
 
 const TARGET_CYCLES: [u64; 3] = [1000, 1000, 1000];
 
-static mut CYCLES_TO_BURN: Vec<u64> = Vec::new();
-
-static mut HISTOGRAMS: Vec<Vec<Histogram<HISTOGRAM_MAX_LATENCY>>> = Vec::new();
-static mut LATENCY_MEASUREMENT_TYPE: LatencyMeasurement =
-    LatencyMeasurement::None;
-
-#[derive(Clone)]
+#[derive(Clone, Default)]
 struct DataStruct {
     _data: f32,
 }
 
+#[derive(Clone, Copy)]
 enum LatencyMeasurement {
     None,
     Total,
@@ -31,73 +40,142 @@ enum LatencyMeasurement {
 }
 
 type MsgData = DataStruct;
-type PipeElem = rpppp::types::PipelineElement<MsgData>;
 
-const PROCESS_PIPELINE: [PipeElem; rpppp::types::PIPELINE_SIZE] = [
-    Some(burn_cycles),
-    Some(burn_cycles),
-    Some(burn_cycles),
-    None,
-    None,
-];
+/// Burns cycles and measures the latency. The latencies are recorded in the
+/// histogram "latency" of the stage.
+#[derive(Clone)]
+struct BurnCycles {
+    cycles: u64,
+    latency_measurement: LatencyMeasurement,
+}
 
-fn burn_cycles(input: &mut Box<rpppp::types::Msg<MsgData>>, core_id: usize) {
-    let msg = input.as_mut();
-    let idx = msg.pipeline_index;
-    unsafe {
-        match LATENCY_MEASUREMENT_TYPE {
+impl Stage<MsgData> for BurnCycles {
+    fn process(
+        &mut self,
+        input: &mut ChannelElement<MsgData>,
+        ctx: &mut StageContext,
+    ) -> StageOutcome {
+        let msg = input.as_mut();
+        match self.latency_measurement {
             LatencyMeasurement::Switching => {
-                HISTOGRAMS[core_id][idx]
-                    .add_value(msg.timestamp.elapsed().as_micros() as usize);
-                tsc::burn(CYCLES_TO_BURN[idx]);
-                msg.timestamp = Instant::now();
+                ctx.record(
+                    "latency",
+                    msg.timestamp.elapsed().as_micros() as usize,
+                );
+                tsc::burn(self.cycles);
+                msg.timestamp = ctx.now();
             }
             LatencyMeasurement::Total => {
-                tsc::burn(CYCLES_TO_BURN[idx]);
-                HISTOGRAMS[core_id][0]
-                    .add_value(msg.timestamp.elapsed().as_micros() as usize);
+                tsc::burn(self.cycles);
+                ctx.record(
+                    "latency",
+                    msg.timestamp.elapsed().as_micros() as usize,
+                );
             }
             LatencyMeasurement::None => {
-                tsc::burn(CYCLES_TO_BURN[idx]);
+                tsc::burn(self.cycles);
             }
         }
+        StageOutcome::Continue
+    }
+}
+
+/// The workload, from the arguments after the number of packets: the burst
+/// size, the size of the message pool and the number of flows. Each defaults
+/// to the workload of the original measurements, without bursts, without a
+/// pool and with one flow. A pool size of zero means no pool.
+#[derive(Clone, Copy)]
+struct Workload {
+    burst_size: usize,
+    pool_size: usize,
+    num_flows: u64,
+}
+
+impl Workload {
+    fn from_args(args: &[String]) -> Self {
+        Workload {
+            burst_size: arg(args, 4, DEFAULT_BURST_SIZE),
+            pool_size: arg(args, 5, DEFAULT_POOL_SIZE),
+            num_flows: arg(args, 6, DEFAULT_NUM_FLOWS),
+        }
+    }
+}
+
+/// The argument at `index`, or `default` if it was not given
+fn arg<T: FromStr>(args: &[String], index: usize, default: T) -> T
+where
+    T::Err: Debug,
+{
+    args.get(index).map_or(default, |arg| arg.parse().unwrap())
+}
+
+/// Creates the pipeline that is run on every message, one stage per target
+fn process_pipeline(
+    cycles_to_burn: &[u64],
+    latency_measurement: LatencyMeasurement,
+    workload: Workload,
+) -> Arc<Pipeline<MsgData>> {
+    let mut builder = Pipeline::builder();
+    for &cycles in cycles_to_burn {
+        builder = builder.stage(StageDescriptor::from_stage(
+            QUEUE_TYPE,
+            BurnCycles {
+                cycles,
+                latency_measurement,
+            },
+        ));
+    }
+    let builder = builder
+        .work_stealing(WORK_STEALING)
+        .admission(ADMISSION)
+        .burst_size(workload.burst_size);
+    match workload.pool_size {
+        0 => builder,
+        size => builder.message_pool(MsgPool::new(size)),
     }
+    .build()
+    .unwrap()
 }
 
 /// Generates the traffic that will be handled by rpppp
 async fn generate_traffic(
-    shard: ShardReturnRequest<MsgData>,
-    stop_time: Instant,
-) -> (rpppp::core::ShardReturnRequest<MsgData>, u64) {
+    mut injector: Injector<MsgData>,
+    num_flows: u64,
+) -> Injector<MsgData> {
     // Somehow it is faster to generate random data than to use 0, even though
     // the data isn't used
     let mut rng = rand::thread_rng();
     let data_distribution = Uniform::from(0_f32..=10_000_f32);
+    let flow_distribution = Uniform::from(0..num_flows);
 
-    let mut packets_sent = 0_u64;
-
-    while stop_time > Instant::now() {
-        shard
-            .send_to(
-                rpppp::core::round_robin_get_next_shard(
-                    shard.nr_shards(),
-                    packets_sent as usize,
-                ),
-                Box::new(rpppp::types::Msg {
-                    data: DataStruct {
-                        _data: data_distribution.sample(&mut rng),
-                    },
-                    pipeline: &PROCESS_PIPELINE,
-                    pipeline_index: 0,
-                    timestamp: Instant::now(),
-                }),
-            )
-            .await
-            .unwrap();
-        packets_sent += 1;
+    while !injector.limit_reached() {
+        let msg = injector.alloc(
+            DataStruct {
+                _data: data_distribution.sample(&mut rng),
+            },
+            flow_distribution.sample(&mut rng),
+        );
+        match msg {
+            Some(msg) => {
+                injector.inject(msg).await;
+            }
+            // Wait for messages to return to the pool
+            None => glommio::executor().yield_now().await,
+        }
     }
 
-    (shard, packets_sent)
+    injector
+}
+
+/// The run limit given by the number of packets to inject, like `-n` of the
+/// C pipeline. Zero runs until Ctrl-C, and without it the run lasts for
+/// [`TEST_DURATION`].
+fn run_limit(num_packets: Option<String>) -> RunLimit {
+    match num_packets.map(|n| n.parse::<u64>().unwrap()) {
+        None => RunLimit::after(TEST_DURATION),
+        Some(0) => RunLimit::Until(CancellationToken::on_sigint().unwrap()),
+        Some(n) => RunLimit::PacketCount(n),
+    }
 }
 
 fn get_total_work_per_packet() -> u64 {
@@ -111,16 +189,25 @@ fn get_total_work_per_packet() -> u64 {
 fn main() {
     let starting_time = Instant::now();
 
-    let (worker_cores, num_workers, num_cores, num_stages) = setup();
+    let (worker_cores, num_workers, latency_measurement, cycles_to_burn) =
+        setup();
+    let args: Vec<String> = env::args().collect();
+    let workload = Workload::from_args(&args);
+    let pipeline =
+        process_pipeline(&cycles_to_burn, latency_measurement, workload);
 
     // Run the simulation
-    let (run_duration, packets_processed) = rpppp::core::start_dsw(
+    let report = rpppp::core::start_dsw(
         worker_cores,
         GENERATOR_CORE,
-        generate_traffic,
-        Instant::now() + TEST_DURATION,
-    );
+        pipeline,
+        DISPATCH_POLICY,
+        move |injector, _| generate_traffic(injector, workload.num_flows),
+        run_limit(args.get(3).cloned()),
+    )
+    .unwrap();
 
+    let run_duration = report.run_duration;
     let s = format!(
         "Run duration {:.2}\tending time {:.2}\tdiff {:.2}",
         run_duration.as_secs_f32(),
@@ -131,108 +218,108 @@ fn main() {
     println!("{s}");
 
     post_print(
-        packets_processed,
-        num_cores,
-        run_duration,
+        &report,
         num_workers,
-        num_stages,
+        cycles_to_burn.len(),
+        latency_measurement,
+    );
+
+    println!("# UTIL");
+    for utilisation in report.utilisation() {
+        println!("{utilisation:.4}");
+    }
+
+    println!("# STOLEN");
+    for worker in &report.workers {
+        println!("{}", worker.stolen);
+    }
+
+    if let Some(pool) = report.pool {
+        println!("# POOL");
+        println!("size {}\texhausted {}", pool.size, pool.exhausted);
+    }
+
+    println!("# ADMISSION");
+    println!(
+        "rejected {}\tdelayed {}",
+        report.rejected_injections, report.delayed_injections
+    );
+
+    println!("# DRAIN");
+    println!(
+        "duration {:.4}\tabandoned {}",
+        report.drain_duration.as_secs_f64(),
+        report.abandoned_packets
     );
 }
 
 /// Set up and calibrate before run
-fn setup() -> (Vec<u16>, usize, usize, usize) {
+fn setup() -> (Vec<u16>, usize, LatencyMeasurement, Vec<u64>) {
     let args: Vec<String> = env::args().collect();
 
-    unsafe {
-        LATENCY_MEASUREMENT_TYPE = match &args[1][..] {
-            "0" => LatencyMeasurement::None,
-            "1" => LatencyMeasurement::Total,
-            "2" => LatencyMeasurement::Switching,
-            x => {
-                panic!("Latency measurement type {} not supported!", x);
-            }
-        };
-    }
+    let latency_measurement = match &args[1][..] {
+        "0" => LatencyMeasurement::None,
+        "1" => LatencyMeasurement::Total,
+        "2" => LatencyMeasurement::Switching,
+        x => {
+            panic!("Latency measurement type {} not supported!", x);
+        }
+    };
 
     // Ensure that no interrupts during calibration. They only happen on core 0
-    LocalExecutorBuilder::new(Placement::Fixed(GENERATOR_CORE as usize))
-        .name("calibrator")
-        .spawn(move || async move {
-            unsafe { CYCLES_TO_BURN = rpppp::tsc::calibrate(&TARGET_CYCLES) };
-        })
-        .unwrap()
-        .join()
-        .unwrap();
+    let cycles_to_burn =
+        LocalExecutorBuilder::new(Placement::Fixed(GENERATOR_CORE as usize))
+            .name("calibrator")
+            .spawn(move || async move { rpppp::tsc::calibrate(&TARGET_CYCLES) })
+            .unwrap()
+            .join()
+            .unwrap();
 
     let worker_cores: Vec<_> = args[2]
         .split(",")
         .map(|x| x.parse::<u16>().unwrap())
         .collect();
     let num_workers = worker_cores.len();
-    let num_cores = num_workers + 1; // +1 from generator
-
-    let num_histograms = num_workers;
-    let num_stages = PROCESS_PIPELINE.iter().filter(|p| p.is_some()).count();
-
-    unsafe {
-        HISTOGRAMS.clear();
-        for i in 0..num_histograms {
-            HISTOGRAMS.push(Vec::new());
-            for _ in 0..num_stages {
-                HISTOGRAMS[i].push(Histogram::new())
-            }
-        }
-    }
 
     println!("Using worker cores: {:?}", worker_cores);
-    (worker_cores, num_workers, num_cores, num_stages)
+    (
+        worker_cores,
+        num_workers,
+        latency_measurement,
+        cycles_to_burn,
+    )
 }
 
 /// Print the collected data
 fn post_print(
-    packets_processed: u64,
-    num_cores: usize,
-    run_duration: Duration,
+    report: &RunReport,
     num_workers: usize,
     num_stages: usize,
+    latency_measurement: LatencyMeasurement,
 ) {
-    match unsafe { &LATENCY_MEASUREMENT_TYPE } {
+    match latency_measurement {
         LatencyMeasurement::None => {
-            let done_work = packets_processed * get_total_work_per_packet();
-            let ideal_work = (num_cores as f64
-                * run_duration.as_secs_f64()
-                * get_tsc_hz() as f64) as u64;
-
             println!(
                 "# AVG\n{}\t{}\t{}\t{}",
                 num_workers,
-                packets_processed as f64 / run_duration.as_micros() as f64,
-                done_work,
-                ideal_work
+                report.throughput_mpps(),
+                report.done_work(get_total_work_per_packet()),
+                report.ideal_work(get_tsc_hz())
             );
         }
         LatencyMeasurement::Total => {
             println!("# TL");
-            let mut total_hist: Histogram<HISTOGRAM_MAX_LATENCY> =
-                Histogram::new();
-            unsafe {
-                HISTOGRAMS
-                    .iter()
-                    .for_each(|hist| total_hist.add_data_from(&hist[0]));
-            }
-            total_hist.print(false);
+            report.histogram("latency").unwrap_or_default().print(false);
             println!();
         }
         LatencyMeasurement::Switching => {
             for stage in 0..num_stages {
                 println!("# TSL-{stage}");
-                let mut total_hist: Histogram<HISTOGRAM_MAX_LATENCY> =
-                    Histogram::new();
-                unsafe {
-                    HISTOGRAMS.iter().for_each(|hist| {
-                        total_hist.add_data_from(&hist[stage])
-                    });
-                }
+                let mut total_hist = report
+                    .stats
+                    .histogram(stage, "latency")
+                    .cloned()
+                    .unwrap_or_default();
                 total_hist.print(false);
                 println!();
             }
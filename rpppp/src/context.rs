@@ -0,0 +1,190 @@
+use std::{
+    any::{Any, TypeId},
+    collections::{BTreeMap, HashMap},
+    fmt,
+    time::Instant,
+};
+
+use crate::histogram::Histogram;
+
+/// The number of values in the contiguous part of the histograms of
+/// [`Stats`].
+pub const STATS_HISTOGRAM_SIZE: usize = 100_000;
+pub type StatsHistogram = Histogram<STATS_HISTOGRAM_SIZE>;
+
+/// Counters and histograms recorded by the stages. They are identified by the
+/// index of the stage that recorded them and a name.
+#[derive(Clone, Default)]
+pub struct Stats {
+    counters: BTreeMap<(usize, &'static str), u64>,
+    histograms: BTreeMap<(usize, &'static str), StatsHistogram>,
+}
+
+impl Stats {
+    /// The value of the counter `name` of stage `stage`.
+    pub fn counter(&self, stage: usize, name: &'static str) -> u64 {
+        self.counters.get(&(stage, name)).copied().unwrap_or(0)
+    }
+
+    /// The sum of the counters `name` of all stages.
+    pub fn total_counter(&self, name: &str) -> u64 {
+        self.counters
+            .iter()
+            .filter(|((_, n), _)| *n == name)
+            .map(|(_, value)| value)
+            .sum()
+    }
+
+    /// The histogram `name` of stage `stage`.
+    pub fn histogram(
+        &self,
+        stage: usize,
+        name: &'static str,
+    ) -> Option<&StatsHistogram> {
+        self.histograms.get(&(stage, name))
+    }
+
+    /// The histograms `name` of all stages combined.
+    pub fn total_histogram(&self, name: &str) -> StatsHistogram {
+        let mut total = StatsHistogram::new();
+        self.histograms
+            .iter()
+            .filter(|((_, n), _)| *n == name)
+            .for_each(|(_, histogram)| total.add_data_from(histogram));
+        total
+    }
+
+    /// Adds the counters and histograms of `other`.
+    pub fn merge(&mut self, other: Stats) {
+        for (key, value) in other.counters {
+            *self.counters.entry(key).or_insert(0) += value;
+        }
+        for (key, histogram) in other.histograms {
+            match self.histograms.get_mut(&key) {
+                Some(own) => own.add_data_from(&histogram),
+                None => {
+                    self.histograms.insert(key, histogram);
+                }
+            }
+        }
+    }
+}
+
+// The histograms are too large to print
+impl fmt::Debug for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stats")
+            .field("counters", &self.counters)
+            .field(
+                "histograms",
+                &self
+                    .histograms
+                    .iter()
+                    .map(|(key, histogram)| (key, histogram.count()))
+                    .collect::<BTreeMap<_, _>>(),
+            )
+            .finish()
+    }
+}
+
+/// Given to a stage every time it processes a message. Owned by the worker,
+/// so everything in it is shared by all stages on the worker but never
+/// between workers.
+pub struct StageContext {
+    worker_id: usize,
+    stage_index: usize,
+    local: HashMap<TypeId, Box<dyn Any>>,
+    stats: Stats,
+}
+
+impl StageContext {
+    pub(crate) fn new(worker_id: usize) -> Self {
+        StageContext {
+            worker_id,
+            stage_index: 0,
+            local: HashMap::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    pub(crate) fn set_stage_index(&mut self, stage_index: usize) {
+        self.stage_index = stage_index;
+    }
+
+    /// Gives back the statistics once the worker is done
+    pub(crate) fn take_stats(&mut self) -> Stats {
+        std::mem::take(&mut self.stats)
+    }
+
+    /// The id of the worker running the stage.
+    pub fn worker_id(&self) -> usize {
+        self.worker_id
+    }
+
+    /// The index in the pipeline of the running stage.
+    pub fn stage_index(&self) -> usize {
+        self.stage_index
+    }
+
+    /// The current time.
+    pub fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// The value of type `T` stored on this worker. Created with
+    /// [`Default`] the first time it is used.
+    pub fn local<T: Default + 'static>(&mut self) -> &mut T {
+        self.local
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::<T>::default())
+            .downcast_mut()
+            .unwrap()
+    }
+
+    /// Adds `value` to the counter `name` of the running stage.
+    pub fn count(&mut self, name: &'static str, value: u64) {
+        *self
+            .stats
+            .counters
+            .entry((self.stage_index, name))
+            .or_insert(0) += value;
+    }
+
+    /// Adds `value` to the histogram `name` of the running stage.
+    pub fn record(&mut self, name: &'static str, value: usize) {
+        self.stats
+            .histograms
+            .entry((self.stage_index, name))
+            .or_default()
+            .add_value(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut contexts = [StageContext::new(0), StageContext::new(1)];
+        for (worker_id, ctx) in contexts.iter_mut().enumerate() {
+            for stage in 0..2 {
+                ctx.set_stage_index(stage);
+                ctx.count("messages", 1 + worker_id as u64);
+                ctx.record("latency", stage);
+                *ctx.local::<u64>() += 1;
+            }
+            assert_eq!(*ctx.local::<u64>(), 2);
+        }
+
+        let mut stats = Stats::default();
+        for ctx in contexts.iter_mut() {
+            stats.merge(ctx.take_stats());
+        }
+        assert_eq!(stats.counter(1, "messages"), 3);
+        assert_eq!(stats.total_counter("messages"), 6);
+        assert_eq!(stats.histogram(0, "latency").unwrap().count(), 2);
+        assert_eq!(stats.total_histogram("latency").count(), 4);
+        assert!(stats.histogram(2, "latency").is_none());
+    }
+}
@@ -1,25 +1,50 @@
 use futures_lite::{future::ready, FutureExt};
 use glommio::{
     channels::{
-        channel_mesh::{self, Receivers},
-        sharding::{Handler, HandlerResult, Sharded},
+        channel_mesh::{Receivers, Senders},
+        sharding::{Handler, HandlerResult},
         shared_channel,
     },
-    timer::sleep,
+    sync::Semaphore,
+    timer,
 };
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
     rc::Rc,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use crate::types::{
-    ChannelElement, ControlMesh, ControlMessage, DataMesh,
-    CONTROL_MESH_CONTROLLER_ID, DATA_MESH_CONTROLLER_ID,
+use crate::{
+    admission::Credits,
+    context::{Stats, StatsHistogram},
+    dispatch::Dispatcher,
+    error::Error,
+    failure::{Failures, LostMessage},
+    limit::RunLimit,
+    mesh::{MeshConsumers, MeshShard},
+    pipeline::{FanOut, Pipeline, StageOutcome},
+    pool::LocalPool,
+    priority::{PriorityQueues, PRIORITY_CLASSES},
+    reorder::{ReorderBuffer, Sequencer},
+    types::{
+        Burst, ChannelElement, ControlMesh, ControlMessage, DataMesh, Msg,
+        QueueType, RunReport, CONTROL_MESH_CONTROLLER_ID,
+        DATA_MESH_CONTROLLER_ID, MESH_CHANNEL_SIZE,
+    },
 };
 
-pub type ShardReturnRequest<MsgData> =
-    Sharded<ChannelElement<MsgData>, ReturnRequestHandler<MsgData>>;
+/// Messages returning from an ordered stage, reordered per stage and flow
+type OrderedReturns<MsgData> =
+    ReorderBuffer<(usize, u64), ChannelElement<MsgData>>;
+/// Messages waiting to be sent and the shards to send them to
+type OutgoingMessages<MsgData> =
+    PriorityQueues<(usize, ChannelElement<MsgData>)>;
+/// The ends of the control mesh that belong to the controller
+type ControlChannels = (Senders<ControlMessage>, Receivers<ControlMessage>);
+/// Number of discarded messages per stage and reason
+type DiscardCounts = BTreeMap<(usize, &'static str), u64>;
 
 /// Gets the next shard
 pub fn round_robin_get_next_shard(
@@ -30,197 +55,907 @@ pub fn round_robin_get_next_shard(
     (prev_shard % (nr_shards - 1)) + 1
 }
 
-/// Increments rr based on nr_shards
-pub fn increment_round_robin(rr: &RefCell<usize>, nr_shards: usize) -> usize {
-    let mut rr_counter = rr.borrow_mut();
-    *rr_counter = round_robin_get_next_shard(nr_shards, *rr_counter);
-    *rr_counter
+/// Gets the shard that owns `flow_id` at pipeline stage `stage`
+pub fn flow_get_shard(nr_shards: usize, flow_id: u64, stage: usize) -> usize {
+    // The stage is mixed into the hash so that the flows are balanced
+    // differently over the workers at each stage
+    let hash =
+        (flow_id ^ ((stage as u64) << 48)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    // Ignores shard 0, which is the scheduler
+    ((hash >> 32) as usize % (nr_shards - 1)) + 1
 }
 
 #[derive(Clone)]
 pub struct ReturnRequestHandler<MsgData: Send + 'static> {
-    shard: *mut *mut ShardReturnRequest<MsgData>,
-    pub return_counter: RefCell<Rc<u64>>,
-    pub processed_packets: RefCell<Rc<u64>>,
-    rr_counter: RefCell<usize>,
-    stop_time: Instant,
+    shard: MeshShard<Burst<MsgData>>,
+    // Messages and copies that have left the pipeline
+    returned: Rc<Cell<u64>>,
+    processed_packets: Rc<Cell<u64>>,
+    abandoned: Rc<Cell<u64>>,
+    lost: Rc<Cell<u64>>,
+    // The number of injected messages, once no more are injected
+    injected: Rc<Cell<Option<u64>>>,
+    // Signalled when the last message has left the pipeline
+    drained: Rc<Semaphore>,
+    // Whether the messages returning to the controller leave the pipeline
+    // without running their remaining stages
+    abandoning: Rc<Cell<bool>>,
+    copies: Rc<Cell<u64>>,
+    drops: Rc<RefCell<DiscardCounts>>,
+    errors: Rc<RefCell<DiscardCounts>>,
+    sequencer: Rc<RefCell<Sequencer>>,
+    reorder_buffer: Rc<RefCell<OrderedReturns<MsgData>>>,
+    outgoing: Rc<RefCell<OutgoingMessages<MsgData>>>,
+    sending: Rc<Cell<bool>>,
+    latency: Rc<RefCell<Vec<StatsHistogram>>>,
+    credits: Rc<Credits>,
+    // The cache of the message pool of the pipeline on this core, if any
+    pool: Rc<RefCell<Option<LocalPool<MsgData>>>>,
+    dispatcher: Rc<Dispatcher>,
+    pipeline: Arc<Pipeline<MsgData>>,
+    limit: RunLimit,
+    failures: Arc<Failures>,
 }
 
-impl<MsgData: Send + Clone> Handler<ChannelElement<MsgData>>
+impl<MsgData: Send + Clone> Handler<Burst<MsgData>>
     for ReturnRequestHandler<MsgData>
 {
     fn handle(
         &self,
-        message: ChannelElement<MsgData>,
+        burst: Burst<MsgData>,
         _src_shard: usize,
         _cur_shard: usize,
     ) -> HandlerResult {
-        if self.stop_time > Instant::now() {
-            // Still work to do, so sent it to a worker
-            if message.pipeline[message.pipeline_index].is_some() {
-                let shard_pointer = self.shard;
-
-                let nr_shards = unsafe {
-                    shard_pointer
-                        .as_ref()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .nr_shards()
-                };
-
-                let next_shard =
-                    increment_round_robin(&self.rr_counter, nr_shards);
-
-                return Box::pin(async move {
-                    let shard = unsafe {
-                        shard_pointer.as_ref().unwrap().as_ref().unwrap()
-                    };
-                    shard.send_to(next_shard, message).await.unwrap();
-                });
+        for mut message in burst {
+            if let Some(slot) = message.released_slot.take() {
+                self.dispatcher.flows().done(slot);
+            }
+
+            if let Some(stage) = message.ordered_stage.take() {
+                // Restore the order of the flow before it continues
+                let flow_id = message.flow_id;
+                self.reorder_buffer.borrow_mut().insert(
+                    (stage, flow_id),
+                    message.seq,
+                    message,
+                    |message| self.route_all(message, |send| self.push(send)),
+                );
+                self.forget_flow(stage, flow_id);
+            } else {
+                self.route_all(message, |send| self.push(send));
             }
-            // Counts how many messages have been sent
-            unsafe {
-                *Rc::get_mut_unchecked(
-                    &mut self.processed_packets.borrow_mut(),
-                ) += 1
-            };
         }
-        // Counts how many messages have been sent
-        unsafe {
-            *Rc::get_mut_unchecked(&mut self.return_counter.borrow_mut()) += 1
-        };
-        ready(()).boxed_local()
+        for lost in self.failures.take_lost() {
+            self.count_lost(lost);
+        }
+        if self.failures.aborted() {
+            // Stops waiting for the pipeline to drain
+            self.drained.signal(1);
+        }
+        self.send_outgoing()
     }
 }
 
+/// Groups `messages` by the shard to send them to, keeping their order.
+fn into_bursts<MsgData>(
+    messages: Vec<(usize, ChannelElement<MsgData>)>,
+) -> Vec<(usize, Burst<MsgData>)> {
+    let mut bursts: Vec<(usize, Burst<MsgData>)> = Vec::new();
+    for (shard, message) in messages {
+        match bursts
+            .iter_mut()
+            .find(|(burst_shard, _)| *burst_shard == shard)
+        {
+            Some((_, burst)) => burst.push(message),
+            None => bursts.push((shard, vec![message])),
+        }
+    }
+    bursts
+}
+
 impl<MsgData: Send + Clone> ReturnRequestHandler<MsgData> {
-    fn new(stop_time: Instant) -> Self {
+    fn new(
+        pipeline: Arc<Pipeline<MsgData>>,
+        dispatcher: Dispatcher,
+        limit: RunLimit,
+        failures: Arc<Failures>,
+        shard: MeshShard<Burst<MsgData>>,
+    ) -> Self {
         ReturnRequestHandler {
-            shard: Box::into_raw(Box::new(std::ptr::null_mut())),
-            return_counter: RefCell::new(Rc::new(0)),
-            processed_packets: RefCell::new(Rc::new(0)),
-            rr_counter: RefCell::new(0),
-            stop_time,
+            shard,
+            returned: Rc::new(Cell::new(0)),
+            processed_packets: Rc::new(Cell::new(0)),
+            abandoned: Rc::new(Cell::new(0)),
+            lost: Rc::new(Cell::new(0)),
+            injected: Rc::new(Cell::new(None)),
+            drained: Rc::new(Semaphore::new(0)),
+            abandoning: Rc::new(Cell::new(false)),
+            copies: Rc::new(Cell::new(0)),
+            drops: Rc::new(RefCell::new(BTreeMap::new())),
+            errors: Rc::new(RefCell::new(BTreeMap::new())),
+            sequencer: Rc::new(RefCell::new(Sequencer::default())),
+            reorder_buffer: Rc::new(RefCell::new(ReorderBuffer::new())),
+            outgoing: Rc::new(RefCell::new(PriorityQueues::new(
+                pipeline.priority_mode(),
+            ))),
+            sending: Rc::new(Cell::new(false)),
+            latency: Rc::new(RefCell::new(vec![
+                StatsHistogram::new();
+                PRIORITY_CLASSES
+            ])),
+            credits: Rc::new(Credits::new(pipeline.admission())),
+            pool: Rc::new(RefCell::new(
+                pipeline.message_pool().map(|pool| pool.local()),
+            )),
+            dispatcher: Rc::new(dispatcher),
+            pipeline,
+            limit,
+            failures,
+        }
+    }
+
+    /// Gets the shard that will process the next stage of `message`. Messages
+    /// entering an ordered stage are given their sequence number.
+    fn next_shard(&self, message: &mut ChannelElement<MsgData>) -> usize {
+        // Assume that there is work in the pipeline
+        let queue_type = self
+            .pipeline
+            .stage(message.pipeline_index)
+            .unwrap()
+            .queue_type;
+        if queue_type == QueueType::Ordered {
+            self.sequencer.borrow_mut().stamp(message);
+        }
+        if let Some(target_shard) = message.target_shard {
+            return self.dispatcher.to_shard(target_shard);
+        }
+        self.dispatcher.next_shard(message, queue_type)
+    }
+
+    /// Queues `message` to be sent to `shard` in the priority class of its
+    /// next stage
+    fn push(&self, (shard, message): (usize, ChannelElement<MsgData>)) {
+        let class = self.pipeline.priority_class(&message);
+        self.outgoing.borrow_mut().push(class, (shard, message));
+    }
+
+    /// Sends the queued messages, highest priority first and in bursts per
+    /// shard, unless another task already does. Messages from different
+    /// workers are handled concurrently, so only one task at a time sends to
+    /// keep the messages of each priority class in order, like the released
+    /// messages of an ordered flow.
+    fn send_outgoing(&self) -> HandlerResult {
+        if self.sending.get() || self.outgoing.borrow().is_empty() {
+            return ready(()).boxed_local();
         }
+        self.sending.set(true);
+        let handler = self.clone();
+        let burst_size = self.pipeline.burst_size();
+        Box::pin(async move {
+            loop {
+                let next: Vec<_> =
+                    std::iter::from_fn(|| handler.outgoing.borrow_mut().pop())
+                        .take(burst_size)
+                        .collect();
+                if next.is_empty() {
+                    break;
+                }
+                for (next_shard, burst) in into_bursts(next) {
+                    handler.send(next_shard, burst).await;
+                }
+            }
+            handler.sending.set(false);
+        })
     }
 
-    fn set_shard(&mut self, shard: &mut ShardReturnRequest<MsgData>) {
-        // Handler must be created before the shard, but the shard must be
-        // saved in the handler, so therefore the unsafe pointer
-        unsafe {
-            *self.shard.as_mut().unwrap() = shard;
+    /// Sends `burst` to the shard with id `shard`. The run is aborted if the
+    /// channel is closed, since the messages can not go on.
+    async fn send(&self, shard: usize, burst: Burst<MsgData>) {
+        if let Err(error) = self.shard.send_to(shard, burst).await {
+            self.failures.fail(error);
+        }
+    }
+
+    /// Routes `message`, or all its copies if it fans out. `send` is called
+    /// with every message that has more work to do and the shard to send it
+    /// to.
+    fn route_all(
+        &self,
+        mut message: ChannelElement<MsgData>,
+        mut send: impl FnMut((usize, ChannelElement<MsgData>)),
+    ) {
+        let Some(fan_out) = message.fan_out.take() else {
+            if let Some(routed) = self.route(message) {
+                send(routed);
+            }
+            return;
+        };
+
+        let successors = self.pipeline.successors(message.pipeline_index);
+        let mut copies: Vec<_> = match fan_out {
+            FanOut::Successors => {
+                successors.iter().map(|&stage| (stage, None)).collect()
+            }
+            FanOut::Workers => {
+                // Ignores shard 0, which is the scheduler
+                (1..self.shard.nr_shards())
+                    .map(|shard| (successors[0], Some(shard)))
+                    .collect()
+            }
+        };
+
+        // The message itself is the last copy, so that it can go back to the
+        // message pool
+        let extra_copies = copies.len() as u64 - 1;
+        self.copies.set(self.copies.get() + extra_copies);
+        self.credits.take(extra_copies);
+        let mut route_copy =
+            |mut copy: ChannelElement<MsgData>, (stage, target_shard)| {
+                copy.pipeline_index = stage;
+                copy.target_shard = target_shard;
+                if let Some(routed) = self.route(copy) {
+                    send(routed);
+                }
+            };
+        let last = copies.pop().unwrap();
+        for copy_to in copies {
+            let mut copy = message.clone();
+            copy.pooled = false;
+            route_copy(copy, copy_to);
+        }
+        route_copy(message, last);
+    }
+
+    /// Checks if all messages have left the pipeline, once no more messages
+    /// are injected.
+    fn all_returned(&self) -> bool {
+        self.injected
+            .get()
+            .map(|injected| injected + self.copies.get())
+            == Some(self.returned.get())
+    }
+
+    /// Waits until all messages have left the pipeline.
+    async fn drain(&self) {
+        while !self.all_returned() {
+            self.drained.acquire(1).await.unwrap();
+        }
+    }
+
+    /// Waits until all messages have left the pipeline, or until the run is
+    /// aborted.
+    async fn settle(&self) {
+        while !self.all_returned() && !self.failures.aborted() {
+            self.drained.acquire(1).await.unwrap();
+        }
+    }
+
+    /// Decides what happens to a message that has returned to the controller.
+    /// Returns the shard to send it to if there is more work to do, otherwise
+    /// the message leaves the pipeline. Discarded messages are counted per
+    /// stage and reason.
+    fn route(
+        &self,
+        mut message: ChannelElement<MsgData>,
+    ) -> Option<(usize, ChannelElement<MsgData>)> {
+        let counts = match message.discarded {
+            Some(StageOutcome::Drop(reason)) => Some((&self.drops, reason)),
+            Some(StageOutcome::Error(reason)) => Some((&self.errors, reason)),
+            _ => None,
+        };
+        if let Some((counts, reason)) = counts {
+            // Counts why and where the message was discarded
+            *counts
+                .borrow_mut()
+                .entry((message.pipeline_index, reason))
+                .or_insert(0) += 1;
+        } else if self.pipeline.stage(message.pipeline_index).is_some() {
+            if !self.abandoning.get() {
+                // Still work to do, so sent it to a worker
+                let next_shard = self.next_shard(&mut message);
+                return Some((next_shard, message));
+            }
+            self.abandoned.set(self.abandoned.get() + 1);
+        } else {
+            let class = self.pipeline.priority_class(&message);
+            let latency = message.created.elapsed().as_micros() as usize;
+            self.latency.borrow_mut()[class].add_value(latency);
+            // Counts how many messages have been processed
+            self.processed_packets.set(self.processed_packets.get() + 1);
+        }
+        self.leave();
+        self.recycle(message);
+        None
+    }
+
+    /// Forgets the order of the flow `flow_id` in the ordered stage `stage`
+    /// once none of its messages are in the stage, so that the flows that
+    /// come and go do not take memory for the rest of the run.
+    fn forget_flow(&self, stage: usize, flow_id: u64) {
+        let mut reorder_buffer = self.reorder_buffer.borrow_mut();
+        let Some(released) = reorder_buffer.released((stage, flow_id)) else {
+            return;
+        };
+        if self.sequencer.borrow_mut().forget(stage, flow_id, released) {
+            reorder_buffer.forget((stage, flow_id));
+        }
+    }
+
+    /// Counts a message that was lost in an async stage that panicked, or
+    /// abandoned in an async stage that did not finish in time. The messages
+    /// after it in an ordered flow no longer wait for it.
+    fn count_lost(&self, lost: LostMessage) {
+        if let Some(stage) = lost.ordered_stage {
+            self.reorder_buffer.borrow_mut().skip(
+                (stage, lost.flow_id),
+                lost.seq,
+                |message| self.route_all(message, |send| self.push(send)),
+            );
+            self.forget_flow(stage, lost.flow_id);
+        }
+        match lost.abandoned {
+            true => self.abandoned.set(self.abandoned.get() + 1),
+            false => self.lost.set(self.lost.get() + 1),
+        }
+        self.leave();
+    }
+
+    /// Counts that a message has left the pipeline.
+    fn leave(&self) {
+        // Counts how many messages have been sent
+        self.returned.set(self.returned.get() + 1);
+        if self.all_returned() {
+            self.drained.signal(1);
+        }
+        self.credits.release();
+    }
+
+    /// Gives `message` back to the message pool, if it came from there.
+    fn recycle(&self, message: ChannelElement<MsgData>) {
+        if let Some(pool) = self.pool.borrow_mut().as_mut() {
+            pool.put(message);
+        }
+    }
+
+    /// Collects the results of the run
+    pub fn report(
+        &self,
+        run_duration: Duration,
+        drain_duration: Duration,
+    ) -> RunReport {
+        RunReport {
+            run_duration,
+            drain_duration,
+            cores: 0,
+            injected_packets: self.injected.get().unwrap_or(0),
+            processed_packets: self.processed_packets.get(),
+            returned_packets: self.returned.get(),
+            copies: self.copies.get(),
+            abandoned_packets: self.abandoned.get(),
+            lost_packets: self.lost.get(),
+            drops: self.drops.borrow().clone(),
+            errors: self.errors.borrow().clone(),
+            reorder: self.reorder_buffer.borrow().stats().clone(),
+            stats: Stats::default(),
+            priority_latency: self.latency.borrow().clone(),
+            workers: Vec::new(),
+            rejected_injections: self.credits.rejected(),
+            delayed_injections: self.credits.delayed(),
+            pool: self.pipeline.message_pool().map(|pool| pool.stats()),
         }
     }
 }
 
-/// Waits for all workers to have finished their initialization
-async fn wait_for_worker_init(control_receiver: &Receivers<ControlMessage>) {
+/// Used by the generator to inject messages into the mesh when using the DSW
+/// scheduler. Keeps track of how many messages have been injected. The
+/// messages are sent in bursts per worker, so a generator that pauses should
+/// call [`Injector::flush`] first.
+pub struct Injector<MsgData: Send + 'static> {
+    handler: ReturnRequestHandler<MsgData>,
+    injected: u64,
+    // The messages not yet sent to each shard
+    pending: Vec<Burst<MsgData>>,
+}
+
+impl<MsgData: Send + Clone> Injector<MsgData> {
+    pub(crate) fn new(handler: ReturnRequestHandler<MsgData>) -> Self {
+        let pending =
+            (0..handler.shard.nr_shards()).map(|_| Vec::new()).collect();
+        Injector {
+            handler,
+            injected: 0,
+            pending,
+        }
+    }
+
+    /// Sends `msg` to the worker that will process its first stage, once it
+    /// has been admitted into the pipeline. Returns false if it was rejected,
+    /// in which case the generator should back off for a while, since the
+    /// controller runs on its core. Messages are also rejected once the run
+    /// limit is reached.
+    pub async fn inject(&mut self, mut msg: ChannelElement<MsgData>) -> bool {
+        if self.limit_reached() {
+            self.handler.recycle(msg);
+            return false;
+        }
+        if self.handler.credits.exhausted() {
+            // The messages waiting for their bursts to fill up hold credits,
+            // which they can only give back once they are sent
+            self.flush().await;
+        }
+        if !self.handler.credits.admit().await {
+            self.handler.recycle(msg);
+            // Let the returning messages free up credits
+            glommio::executor().yield_now().await;
+            return false;
+        }
+        let next_shard = self.handler.next_shard(&mut msg);
+        self.pending[next_shard].push(msg);
+        if self.pending[next_shard].len() >= self.handler.pipeline.burst_size()
+        {
+            let burst = std::mem::take(&mut self.pending[next_shard]);
+            self.handler.send(next_shard, burst).await;
+        }
+        // The generator shares the core with the controller, which must get
+        // to handle the returning messages
+        glommio::yield_if_needed().await;
+        self.injected += 1;
+        true
+    }
+
+    /// Takes a message from the message pool of the pipeline, which the
+    /// controller shares with the generator in DSW mode. Returns [`None`] if
+    /// all messages are in use. Allocates the message if the pipeline has no
+    /// message pool.
+    pub fn alloc(
+        &self,
+        data: MsgData,
+        flow_id: u64,
+    ) -> Option<ChannelElement<MsgData>> {
+        match self.handler.pool.borrow_mut().as_mut() {
+            Some(pool) => pool.get(data, flow_id),
+            None => Some(Box::new(Msg::new(data, flow_id))),
+        }
+    }
+
+    /// Sends the messages that are waiting for their burst to fill up.
+    pub async fn flush(&mut self) {
+        for next_shard in 0..self.pending.len() {
+            if !self.pending[next_shard].is_empty() {
+                let burst = std::mem::take(&mut self.pending[next_shard]);
+                self.handler.send(next_shard, burst).await;
+            }
+        }
+    }
+
+    /// The number of messages that have been injected
+    pub fn injected(&self) -> u64 {
+        self.injected
+    }
+
+    /// Checks if the run limit is reached, so the generator should stop.
+    pub fn limit_reached(&self) -> bool {
+        self.handler.limit.is_reached(self.injected)
+    }
+
+    /// Sends the remaining messages once the generator is done
+    pub(crate) async fn finish(mut self) {
+        self.flush().await;
+    }
+}
+
+/// Waits for all workers to send `expected`
+async fn wait_for_workers(
+    control_receiver: &Receivers<ControlMessage>,
+    expected: ControlMessage,
+) -> Result<(), Error> {
     for peer in 0..control_receiver.nr_producers() {
         if peer != control_receiver.peer_id() {
-            let message =
-                control_receiver.recv_from(peer).await.unwrap().unwrap();
-            assert_eq!(message, ControlMessage::WorkerInitializationComplete);
+            let message = control_receiver
+                .recv_from(peer)
+                .await
+                .ok()
+                .flatten()
+                .ok_or(Error::ChannelClosed)?;
+            if message != expected {
+                return Err(Error::UnexpectedControlMessage(message));
+            }
         }
     }
+    Ok(())
 }
 
-/// Initializes the controller and the communication meshes
+/// Sends `message` to all workers
+async fn broadcast(
+    control_sender: &Senders<ControlMessage>,
+    message: ControlMessage,
+) -> Result<(), Error> {
+    for i in 1..control_sender.nr_consumers() {
+        control_sender
+            .send_to(i, message)
+            .await
+            .map_err(|_| Error::ChannelClosed)?;
+    }
+    Ok(())
+}
+
+/// Initializes the controller and the communication meshes. Fails if the
+/// meshes can not be joined, or if the controller does not get its assumed
+/// ids in them.
 pub async fn controller_init<MsgData: Send + Clone>(
     control_mesh: ControlMesh,
     data_mesh: DataMesh<MsgData>,
-    stop_time: Instant,
-) -> (
-    channel_mesh::Senders<ControlMessage>,
-    ReturnRequestHandler<MsgData>,
-    ShardReturnRequest<MsgData>,
-) {
-    let (control_sender, control_receiver) = control_mesh.join().await.unwrap();
-    // We assume a fixed mesh id for the controller.
-    assert_eq!(
-        control_sender.peer_id(),
-        CONTROL_MESH_CONTROLLER_ID,
-        "Control mesh controller doesn't have the assumed ID"
-    );
-
-    let mut handler = ReturnRequestHandler::new(stop_time);
-
-    let mut shard: ShardReturnRequest<MsgData> =
-        Sharded::new(data_mesh, |_, _| 0, handler.clone())
-            .await
-            .unwrap();
-    // We assume a fixed shard id for the controller.
-    assert_eq!(
-        shard.shard_id(),
-        DATA_MESH_CONTROLLER_ID,
-        "Data mesh controller doesn't have the assumed ID"
-    );
+    pipeline: Arc<Pipeline<MsgData>>,
+    dispatcher: Dispatcher,
+    limit: RunLimit,
+    failures: Arc<Failures>,
+) -> Result<
+    (
+        ControlChannels,
+        ReturnRequestHandler<MsgData>,
+        MeshConsumers,
+    ),
+    Error,
+> {
+    let (control_sender, control_receiver) = control_mesh
+        .join()
+        .await
+        .map_err(|error| Error::MeshJoinFailed(error.to_string()))?;
+    // Joins both meshes before failing, since the workers wait for the
+    // controller to join them
+    let (shard, receivers) = MeshShard::join(data_mesh).await?;
 
-    handler.set_shard(&mut shard);
+    // We assume fixed ids for the controller.
+    if control_sender.peer_id() != CONTROL_MESH_CONTROLLER_ID {
+        return Err(Error::ControllerIdMismatch {
+            mesh: "control",
+            expected: CONTROL_MESH_CONTROLLER_ID,
+            actual: control_sender.peer_id(),
+        });
+    }
+    if shard.shard_id() != DATA_MESH_CONTROLLER_ID {
+        return Err(Error::ControllerIdMismatch {
+            mesh: "data",
+            expected: DATA_MESH_CONTROLLER_ID,
+            actual: shard.shard_id(),
+        });
+    }
+
+    let handler =
+        ReturnRequestHandler::new(pipeline, dispatcher, limit, failures, shard);
+    let consumers = receivers.handle_with(handler.clone());
 
-    wait_for_worker_init(&control_receiver).await;
-    (control_sender, handler, shard)
+    wait_for_workers(
+        &control_receiver,
+        ControlMessage::WorkerInitializationComplete,
+    )
+    .await?;
+    Ok(((control_sender, control_receiver), handler, consumers))
 }
 
 /// The main loop of the controller, where data is received from the
-/// generator and dispatched to the workers
+/// generator and dispatched to the workers. Returns how long it ran and the
+/// number of messages sent into the pipeline.
 async fn send_receive<MsgData: Send + Clone>(
     task_receiver: shared_channel::ConnectedReceiver<ChannelElement<MsgData>>,
-    shard: &ShardReturnRequest<MsgData>,
     handler: &ReturnRequestHandler<MsgData>,
-) -> Duration {
+) -> (Duration, u64) {
     let mut sent_messages = 0u64;
     let start_timestamp = Instant::now();
-    while let Some(task) = task_receiver.recv().await {
-        if handler.stop_time > Instant::now() {
-            let next_shard =
-                increment_round_robin(&handler.rr_counter, shard.nr_shards());
+    while let Some(mut task) = task_receiver.recv().await {
+        // Waiting for a credit holds back the generator, once the channel
+        // from it is full
+        if !handler.limit.is_reached(sent_messages)
+            && handler.credits.admit().await
+        {
+            let next_shard = handler.next_shard(&mut task);
 
-            shard.send_to(next_shard, task).await.unwrap();
+            // The new messages are sent in priority order together with the
+            // returning messages
+            handler.push((next_shard, task));
+            handler.send_outgoing().await;
             sent_messages += 1;
-        }
-    }
-    let run_duration = start_timestamp.elapsed();
 
-    while **handler.return_counter.borrow() != sent_messages {
-        sleep(Duration::from_millis(10)).await;
+            // Someone else is sending, so wait for the queue to go down
+            // instead of taking every message from the generator
+            while handler.outgoing.borrow().len() >= MESH_CHANNEL_SIZE {
+                glommio::executor().yield_now().await;
+            }
+        } else {
+            handler.recycle(task);
+        }
     }
-
-    run_duration
+    (start_timestamp.elapsed(), sent_messages)
 }
 
-/// Closes all worker channels
-pub async fn controller_cleanup<MsgData: Send + Clone>(
-    control_sender: channel_mesh::Senders<ControlMessage>,
-    mut shard: ShardReturnRequest<MsgData>,
-) {
-    for i in 1..control_sender.nr_consumers() {
-        control_sender
-            .send_to(i, ControlMessage::Shutdown)
-            .await
-            .unwrap();
+/// Ends the run once `injected` messages have been injected. The messages in
+/// the pipeline get to go through the rest of it until the drain timeout,
+/// after which the remaining ones are abandoned. They are abandoned right
+/// away if the run is aborted. Then the workers are shut
+/// down and their channels closed. Returns the time it took to drain the
+/// pipeline, or [`Error::ChannelClosed`] if a worker could not be reached.
+pub async fn controller_shutdown<MsgData: Send + Clone>(
+    (control_sender, control_receiver): ControlChannels,
+    handler: &ReturnRequestHandler<MsgData>,
+    consumers: MeshConsumers,
+    injected: u64,
+) -> Result<Duration, Error> {
+    let start = Instant::now();
+    handler.injected.set(Some(injected));
+    broadcast(&control_sender, ControlMessage::StopIngress).await?;
+
+    let drain = async {
+        handler.settle().await;
+        Ok(())
+    };
+    if timer::timeout(handler.pipeline.drain_timeout(), drain)
+        .await
+        .is_err()
+        || handler.failures.aborted()
+    {
+        handler.abandoning.set(true);
     }
+    // The workers give back what they have left, which is abandoned if the
+    // drain timed out
+    broadcast(&control_sender, ControlMessage::Drain).await?;
+    wait_for_workers(&control_receiver, ControlMessage::DrainComplete).await?;
+    // Messages may still be on their way to the controller. After a failure
+    // some may never arrive, so it waits at most the drain timeout.
+    match handler.failures.aborted() {
+        true => {
+            let drain = async {
+                handler.drain().await;
+                Ok(())
+            };
+            let _ =
+                timer::timeout(handler.pipeline.drain_timeout(), drain).await;
+        }
+        false => handler.drain().await,
+    }
+    let drain_duration = start.elapsed();
 
-    shard.close().await;
+    broadcast(&control_sender, ControlMessage::Shutdown).await?;
+    handler.shard.close()?;
+    consumers.join().await;
+    Ok(drain_duration)
 }
 
-/// Initializes and runs the controller. Returns the time it took for the
-/// workers to process all the messages.
+/// Initializes and runs the controller. Returns the results of the run.
 pub async fn run_controller<MsgData: Send + Clone>(
     task_receiver: shared_channel::SharedReceiver<ChannelElement<MsgData>>,
     data_mesh: DataMesh<MsgData>,
     control_mesh: ControlMesh,
-    stop_time: Instant,
-) -> (Duration, u64) {
-    let (control_sender, handler, shard) =
-        controller_init(control_mesh, data_mesh, stop_time).await;
+    pipeline: Arc<Pipeline<MsgData>>,
+    dispatcher: Dispatcher,
+    limit: RunLimit,
+    failures: Arc<Failures>,
+) -> Result<RunReport, Error> {
+    let (control, handler, consumers) = controller_init(
+        control_mesh,
+        data_mesh,
+        pipeline,
+        dispatcher,
+        limit,
+        failures,
+    )
+    .await?;
 
     let task_receiver = task_receiver.connect().await;
     // Send and receive data
-    let run_duration = send_receive(task_receiver, &shard, &handler).await;
+    let (run_duration, injected) = send_receive(task_receiver, &handler).await;
 
-    let processed_packets = **handler.processed_packets.borrow();
-    controller_cleanup(control_sender, shard).await;
+    let drain_duration =
+        controller_shutdown(control, &handler, consumers, injected).await?;
+    Ok(handler.report(run_duration, drain_duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        admission::Admission,
+        context::StageContext,
+        dispatch::{DispatchPolicy, WorkerLoad},
+        flows::FlowTable,
+        pipeline::StageDescriptor,
+    };
+    use glommio::{
+        channels::channel_mesh::MeshBuilder, enclose, LocalExecutor,
+        LocalExecutorBuilder, Placement,
+    };
+
+    fn stage(_msg: &mut ChannelElement<()>, _ctx: &mut StageContext) {}
+
+    /// Creates a handler for `pipeline` on a mesh without workers, so the
+    /// messages must not be sent on
+    async fn handler(pipeline: Arc<Pipeline<()>>) -> ReturnRequestHandler<()> {
+        let dispatcher = Dispatcher::new(
+            DispatchPolicy::RoundRobin,
+            WorkerLoad::new(1),
+            FlowTable::new(2, pipeline.len()),
+        );
+        let failures = Failures::new(pipeline.panic_policy());
+        let (shard, _) =
+            MeshShard::join(MeshBuilder::full(1, 1)).await.unwrap();
+        ReturnRequestHandler::new(
+            pipeline,
+            dispatcher,
+            RunLimit::Unlimited,
+            failures,
+            shard,
+        )
+    }
+
+    #[test]
+    fn test_fan_out() {
+        LocalExecutor::default().run(async {
+            let pipeline = Pipeline::builder()
+                .stage(
+                    StageDescriptor::parallel(stage)
+                        .successors(&["a", "b", "c"]),
+                )
+                .stage(StageDescriptor::parallel(stage).named("a"))
+                .stage(StageDescriptor::parallel(stage).named("b"))
+                .stage(StageDescriptor::parallel(stage).named("c"))
+                .admission(Admission::Reject(3))
+                .build();
+            let handler = handler(pipeline.unwrap()).await;
+            assert!(handler.credits.admit().await);
+
+            let mut msg = Box::new(Msg::new((), 0));
+            msg.fan_out = Some(FanOut::Successors);
+            let mut copies = Vec::new();
+            handler.route_all(msg, |(_, copy)| copies.push(copy));
+            let stages: Vec<_> =
+                copies.iter().map(|copy| copy.pipeline_index).collect();
+            assert_eq!(stages, [1, 2, 3]);
+
+            handler.injected.set(Some(1));
+            for mut copy in copies {
+                assert!(!handler.all_returned());
+                copy.pipeline_index = 4;
+                handler.route_all(copy, |_| unreachable!());
+            }
+            assert!(handler.all_returned());
 
-    (run_duration, processed_packets)
+            let report = handler.report(Duration::ZERO, Duration::ZERO);
+            assert_eq!(report.copies, 2);
+            assert_eq!(report.processed_packets, 3);
+            assert_eq!(report.returned_packets, 3);
+            // Every copy gave its credit back
+            for _ in 0..3 {
+                assert!(handler.credits.admit().await);
+            }
+            assert!(!handler.credits.admit().await);
+        });
+    }
+
+    #[test]
+    fn test_discard_counts() {
+        LocalExecutor::default().run(async {
+            let pipeline =
+                Pipeline::builder().parallel(stage).parallel(stage).build();
+            let handler = handler(pipeline.unwrap()).await;
+            handler.injected.set(Some(5));
+
+            let discarded = [
+                (0, StageOutcome::Drop("filtered")),
+                (1, StageOutcome::Drop("filtered")),
+                (1, StageOutcome::Drop("filtered")),
+                (1, StageOutcome::Error("malformed")),
+            ];
+            for (stage, outcome) in discarded {
+                let mut msg = Box::new(Msg::new((), 0));
+                msg.pipeline_index = stage;
+                msg.discarded = Some(outcome);
+                assert!(handler.route(msg).is_none());
+            }
+            let mut msg = Box::new(Msg::new((), 0));
+            msg.pipeline_index = 2;
+            assert!(handler.route(msg).is_none());
+            assert!(handler.all_returned());
+
+            let report = handler.report(Duration::ZERO, Duration::ZERO);
+            assert_eq!(
+                report.drops,
+                BTreeMap::from([((0, "filtered"), 1), ((1, "filtered"), 2)])
+            );
+            assert_eq!(report.errors, BTreeMap::from([((1, "malformed"), 1)]));
+            assert_eq!(report.processed_packets, 1);
+            assert_eq!(report.returned_packets, 5);
+        });
+    }
+
+    #[test]
+    fn test_flow_get_shard() {
+        let nr_shards = 5;
+        let mut used = [false; 5];
+        for flow_id in 0..64 {
+            for stage in 0..4 {
+                let shard = flow_get_shard(nr_shards, flow_id, stage);
+                assert!(shard > 0 && shard < nr_shards);
+                assert_eq!(shard, flow_get_shard(nr_shards, flow_id, stage));
+                used[shard] = true;
+            }
+        }
+        // All workers should be used
+        assert!(used[1..].iter().all(|u| *u));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let control_mesh = MeshBuilder::full(2, 1);
+        let data_mesh = MeshBuilder::full(2, 1);
+
+        // The controller must get the first id in the meshes, so its
+        // executor is created first
+        let controller = LocalExecutorBuilder::new(Placement::Unbound)
+            .spawn(enclose!((control_mesh, data_mesh) move || async move {
+                let pipeline =
+                    Pipeline::builder().parallel(stage).build().unwrap();
+                let failures = Failures::new(pipeline.panic_policy());
+                let dispatcher = Dispatcher::new(
+                    DispatchPolicy::RoundRobin,
+                    WorkerLoad::new(1),
+                    FlowTable::new(2, pipeline.len()),
+                );
+                let (control, handler, consumers) = controller_init(
+                    control_mesh,
+                    data_mesh,
+                    pipeline,
+                    dispatcher,
+                    RunLimit::Unlimited,
+                    failures,
+                )
+                .await
+                .unwrap();
+                controller_shutdown(control, &handler, consumers, 0)
+                    .await
+                    .unwrap();
+            }))
+            .unwrap();
+
+        // Answers like a worker, and gets the control messages it was sent
+        let worker = LocalExecutorBuilder::new(Placement::Unbound)
+            .spawn(move || async move {
+                let (sender, receiver) = control_mesh.join().await.unwrap();
+                let (shard, _receivers) =
+                    MeshShard::<Burst<()>>::join(data_mesh).await.unwrap();
+                let controller = CONTROL_MESH_CONTROLLER_ID;
+                sender
+                    .send_to(
+                        controller,
+                        ControlMessage::WorkerInitializationComplete,
+                    )
+                    .await
+                    .unwrap();
+                let mut received = Vec::new();
+                while let Ok(Some(message)) =
+                    receiver.recv_from(controller).await
+                {
+                    received.push(message);
+                    match message {
+                        ControlMessage::Drain => sender
+                            .send_to(controller, ControlMessage::DrainComplete)
+                            .await
+                            .unwrap(),
+                        ControlMessage::Shutdown => break,
+                        _ => {}
+                    }
+                }
+                shard.close().unwrap();
+                received
+            })
+            .unwrap();
+
+        controller.join().unwrap();
+        assert_eq!(
+            worker.join().unwrap(),
+            [
+                ControlMessage::StopIngress,
+                ControlMessage::Drain,
+                ControlMessage::Shutdown
+            ]
+        );
+    }
 }
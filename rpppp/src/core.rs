@@ -1,24 +1,37 @@
 use futures::Future;
-use glommio::{channels::shared_channel, prelude::*, timer, CpuSet};
-use std::time::{Duration, Instant};
+use glommio::{
+    channels::shared_channel, enclose, prelude::*, CpuSet, ExecutorJoinHandle,
+    PoolThreadHandles,
+};
+use std::{sync::Arc, time::Instant};
 
 use crate::{
     controller,
-    types::{ChannelElement, SchedulingType},
-    workers,
+    dispatch::{DispatchPolicy, Dispatcher, WorkerLoad},
+    error::Error,
+    failure::Failures,
+    flows::FlowTable,
+    limit::RunLimit,
+    pipeline::Pipeline,
+    types::{ChannelElement, RunReport, SchedulingType, WorkerStats},
+    workers::{self, WorkerReport},
 };
 
-pub use crate::controller::{round_robin_get_next_shard, ShardReturnRequest};
+pub use crate::controller::{round_robin_get_next_shard, Injector};
 
 /// Verifies that the provided cores are valid and contains no duplicates.
 fn verify_core_layout(
-    worker_cores: &Vec<u16>,
+    worker_cores: &[u16],
     generator_core: Option<u16>,
     controller_core: Option<u16>,
-) {
-    assert_ne!(worker_cores.len(), 0, "Must have at least one worker core.");
+) -> Result<(), Error> {
+    if worker_cores.is_empty() {
+        return Err(Error::InvalidCoreLayout(
+            "must have at least one worker core",
+        ));
+    }
 
-    let mut cores: Vec<_> = worker_cores.clone();
+    let mut cores: Vec<_> = worker_cores.to_vec();
 
     if let Some(generator) = generator_core {
         cores.push(generator);
@@ -27,82 +40,212 @@ fn verify_core_layout(
         cores.push(controller);
     }
 
-    let num_cores = cores.len();
-
     cores.sort();
-    Vec::dedup(&mut cores);
-    assert_eq!(
-        cores.len(),
-        num_cores,
-        "CPU cores used contains duplicates."
-    );
+    if let Some(core) = cores.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(Error::DuplicateCore(core[0]));
+    }
 
-    let num_avail_cores = CpuSet::online().unwrap().len();
+    // No core is available if the online cores can not be found
+    let num_avail_cores = CpuSet::online().map_or(0, |online| online.len());
 
     for core in cores {
-        assert!(
-            (core as usize) < num_avail_cores,
-            "Core {core} is not available."
-        );
+        if core as usize >= num_avail_cores {
+            return Err(Error::CoreUnavailable(core));
+        }
+    }
+    Ok(())
+}
+
+/// Verifies that `dispatch_policy` can be used with `nr_workers` workers.
+fn verify_dispatch_policy(
+    dispatch_policy: &DispatchPolicy,
+    nr_workers: usize,
+) -> Result<(), Error> {
+    if let DispatchPolicy::WeightedRoundRobin(weights) = dispatch_policy {
+        if weights.len() != nr_workers {
+            return Err(Error::InvalidDispatchPolicy(
+                "must have one dispatch weight per worker",
+            ));
+        }
+        if weights.contains(&0) {
+            return Err(Error::InvalidDispatchPolicy(
+                "dispatch weights must be at least one",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the workers to exit and adds the statistics recorded by them and
+/// their stages to `report`. Fails if any worker failed.
+fn join_workers(
+    worker_pool: PoolThreadHandles<Result<WorkerReport, Error>>,
+    report: &mut RunReport,
+) -> Result<(), Error> {
+    let worker_reports = worker_pool.join_all();
+    report.workers = vec![WorkerStats::default(); worker_reports.len()];
+    for worker_report in worker_reports {
+        let worker_report = worker_report.map_err(executor_failed)??;
+        report.stats.merge(worker_report.stats);
+        report.workers[worker_report.worker_id] = worker_report.worker_stats;
     }
+    Ok(())
+}
+
+/// Waits for the workers once the controller has failed with `error`, and
+/// aborts the run so that the generator stops too. The workers stop when the
+/// controller closes its control channels, so their own failures only follow
+/// from `error`.
+fn stop_workers(
+    worker_pool: PoolThreadHandles<Result<WorkerReport, Error>>,
+    failures: &Failures,
+    error: Error,
+) -> Error {
+    failures.fail(error.clone());
+    worker_pool.join_all();
+    error
+}
+
+/// Waits for the executor of `handle` to exit and gets its result.
+fn join_executor<T: Send>(
+    handle: ExecutorJoinHandle<Result<T, Error>>,
+) -> Result<T, Error> {
+    handle.join().map_err(executor_failed)?
+}
+
+fn executor_failed<T>(error: GlommioError<T>) -> Error {
+    Error::ExecutorFailed(error.to_string())
 }
 
 /// Starts the RPPPP processes using the DSW scheduler.
 /// - `worker_cores` are the cores that will be allocated workers
 /// - `generator_core` is the core that will generate data and send it to the
 ///   workers
+/// - `pipeline` is the stages that are run on every message
+/// - `dispatch_policy` decides which worker gets the messages of the parallel
+///   and ordered stages
 /// - `generator` is a function that will generate the data for the test, and
-///   send it to the mesh for further processing.
+///   inject it into the mesh for further processing. It should stop once
+///   [`Injector::limit_reached`].
+/// - `limit` decides when no more messages are injected
+///
+/// Fails before the run starts if the cores or the dispatch policy can not be
+/// used. Returns the failure that aborted the run if it was aborted, like
+/// [`Error::StagePanicked`] with
+/// [`PanicPolicy::Abort`](crate::failure::PanicPolicy::Abort).
 pub fn start_dsw<G, F, MsgData: Send + Clone + 'static>(
     worker_cores: Vec<u16>,
     generator_core: u16,
+    pipeline: Arc<Pipeline<MsgData>>,
+    dispatch_policy: DispatchPolicy,
     generator: G,
-    stop_time: Instant,
-) -> (Duration, u64)
+    limit: RunLimit,
+) -> Result<RunReport, Error>
 where
-    G: Fn(ShardReturnRequest<MsgData>, Instant) -> F + Send + 'static,
-    F: Future<Output = (ShardReturnRequest<MsgData>, u64)>,
+    G: Fn(Injector<MsgData>, RunLimit) -> F + Send + 'static,
+    F: Future<Output = Injector<MsgData>>,
 {
-    verify_core_layout(&worker_cores, Some(generator_core), None);
-
-    let generator_handle =
-        LocalExecutorBuilder::new(Placement::Fixed(generator_core as usize))
-            .name("generator")
-            .spawn(move || async move {
-                let (worker_pool, data_mesh, control_mesh) =
-                    workers::spawn_workers(
-                        SchedulingType::Dsw,
-                        &worker_cores,
-                        stop_time,
-                    );
-
-                let (control_sender, handler, shard) =
+    verify_core_layout(&worker_cores, Some(generator_core), None)?;
+    run_dsw(
+        PoolPlacement::Custom(workers::get_cpuset(&worker_cores)?),
+        Placement::Fixed(generator_core as usize),
+        pipeline,
+        dispatch_policy,
+        generator,
+        limit,
+    )
+}
+
+/// Runs [`start_dsw`] with the workers and the generator placed by
+/// `worker_placement` and `generator_placement`.
+pub(crate) fn run_dsw<G, F, MsgData: Send + Clone + 'static>(
+    worker_placement: PoolPlacement,
+    generator_placement: Placement,
+    pipeline: Arc<Pipeline<MsgData>>,
+    dispatch_policy: DispatchPolicy,
+    generator: G,
+    limit: RunLimit,
+) -> Result<RunReport, Error>
+where
+    G: Fn(Injector<MsgData>, RunLimit) -> F + Send + 'static,
+    F: Future<Output = Injector<MsgData>>,
+{
+    let nr_workers = worker_placement.executor_count();
+    verify_dispatch_policy(&dispatch_policy, nr_workers)?;
+    let dispatcher = Dispatcher::new(
+        dispatch_policy,
+        WorkerLoad::new(nr_workers),
+        FlowTable::new(nr_workers + 1, pipeline.len()),
+    );
+    let failures = Failures::new(pipeline.panic_policy());
+    // The run also ends if it is aborted
+    let limit = failures.limit(limit);
+
+    let controller_failures = failures.clone();
+    let generator_handle = LocalExecutorBuilder::new(generator_placement)
+        .name("generator")
+        .spawn(move || async move {
+            let (worker_pool, data_mesh, control_mesh) =
+                workers::spawn_workers(
+                    SchedulingType::Dsw,
+                    worker_placement,
+                    pipeline.clone(),
+                    dispatcher.clone(),
+                    controller_failures.clone(),
+                )?;
+
+            let run = enclose!((controller_failures) async move {
+                let (control, handler, consumers) =
                     controller::controller_init(
                         control_mesh,
                         data_mesh,
-                        stop_time,
+                        pipeline,
+                        dispatcher,
+                        limit.clone(),
+                        controller_failures,
                     )
-                    .await;
+                    .await?;
 
                 let start_timestamp = Instant::now();
                 // Send and receive data
-                let (shard, num_messages) = generator(shard, stop_time).await;
+                let injector =
+                    generator(Injector::new(handler.clone()), limit).await;
                 let run_duration = start_timestamp.elapsed();
+                let num_messages = injector.injected();
+                injector.finish().await;
 
-                // wait until all messages have returned
-                while **handler.return_counter.borrow() != num_messages {
-                    timer::sleep(Duration::from_millis(10)).await;
+                let drain_duration = controller::controller_shutdown(
+                    control,
+                    &handler,
+                    consumers,
+                    num_messages,
+                )
+                .await?;
+                Ok(handler.report(run_duration, drain_duration))
+            });
+            let mut report = match run.await {
+                Ok(report) => report,
+                Err(error) => {
+                    return Err(stop_workers(
+                        worker_pool,
+                        &controller_failures,
+                        error,
+                    ))
                 }
+            };
+            // The generator shares its core with the controller
+            report.cores = nr_workers + 1;
+            join_workers(worker_pool, &mut report)?;
+            Ok(report)
+        })
+        .map_err(executor_failed)?;
 
-                let processed_packets = **handler.processed_packets.borrow();
-
-                controller::controller_cleanup(control_sender, shard).await;
-                worker_pool.join_all();
-                (run_duration, processed_packets)
-            })
-            .unwrap();
-
-    generator_handle.join().unwrap()
+    let report = join_executor(generator_handle);
+    // The failure that aborted the run is why the rest of it failed
+    match failures.take_error() {
+        Some(error) => Err(error),
+        None => report,
+    }
 }
 
 /// Starts the RPPPP processes using the SW scheduler.
@@ -110,18 +253,32 @@ where
 /// - `generator_core` is the core that will generate data
 /// - `controller_core` is the core that will receive data and send it to the
 ///   workers
-/// - `generator` is a function that will generate the data for the test
+/// - `pipeline` is the stages that are run on every message
+/// - `dispatch_policy` decides which worker gets the messages of the parallel
+///   and ordered stages
+/// - `generator` is a function that will generate the data for the test. It
+///   should stop once the limit [`RunLimit::is_reached`] by the messages it
+///   has sent.
+/// - `limit` decides when no more messages are injected. The controller
+///   discards the messages that arrive after it is reached.
+///
+/// Fails before the run starts if the cores or the dispatch policy can not be
+/// used. Returns the failure that aborted the run if it was aborted, like
+/// [`Error::StagePanicked`] with
+/// [`PanicPolicy::Abort`](crate::failure::PanicPolicy::Abort).
 pub fn start_sw<G, F, MsgData: Send + Clone + 'static>(
     worker_cores: Vec<u16>,
     generator_core: u16,
     controller_core: u16,
+    pipeline: Arc<Pipeline<MsgData>>,
+    dispatch_policy: DispatchPolicy,
     generator: G,
-    stop_time: Instant,
-) -> (Duration, u64)
+    limit: RunLimit,
+) -> Result<RunReport, Error>
 where
     G: FnOnce(
             shared_channel::SharedSender<ChannelElement<MsgData>>,
-            Instant,
+            RunLimit,
         ) -> F
         + Send
         + 'static,
@@ -131,42 +288,436 @@ where
         &worker_cores,
         Some(generator_core),
         Some(controller_core),
+    )?;
+    run_sw(
+        PoolPlacement::Custom(workers::get_cpuset(&worker_cores)?),
+        Placement::Fixed(generator_core as usize),
+        Placement::Fixed(controller_core as usize),
+        pipeline,
+        dispatch_policy,
+        generator,
+        limit,
+    )
+}
+
+/// Runs [`start_sw`] with the workers, the generator and the controller
+/// placed by `worker_placement`, `generator_placement` and
+/// `controller_placement`.
+pub(crate) fn run_sw<G, F, MsgData: Send + Clone + 'static>(
+    worker_placement: PoolPlacement,
+    generator_placement: Placement,
+    controller_placement: Placement,
+    pipeline: Arc<Pipeline<MsgData>>,
+    dispatch_policy: DispatchPolicy,
+    generator: G,
+    limit: RunLimit,
+) -> Result<RunReport, Error>
+where
+    G: FnOnce(
+            shared_channel::SharedSender<ChannelElement<MsgData>>,
+            RunLimit,
+        ) -> F
+        + Send
+        + 'static,
+    F: Future<Output = ()> + 'static,
+{
+    let nr_workers = worker_placement.executor_count();
+    verify_dispatch_policy(&dispatch_policy, nr_workers)?;
+    let dispatcher = Dispatcher::new(
+        dispatch_policy,
+        WorkerLoad::new(nr_workers),
+        FlowTable::new(nr_workers + 1, pipeline.len()),
     );
+    let failures = Failures::new(pipeline.panic_policy());
+    // The run also ends if it is aborted
+    let limit = failures.limit(limit);
 
     // For sending data from the generator to the controller
     let (task_sender, task_receiver) = shared_channel::new_bounded(10000);
 
-    let controller_handle =
-        LocalExecutorBuilder::new(Placement::Fixed(controller_core as usize))
-            .name("controller")
-            .spawn(move || async move {
-                let (worker_pool, data_mesh, control_mesh) =
-                    workers::spawn_workers(
-                        SchedulingType::Sw,
-                        &worker_cores,
-                        stop_time,
-                    );
-
-                let run_duration_and_processed_packets =
-                    controller::run_controller(
-                        task_receiver,
-                        data_mesh,
-                        control_mesh,
-                        stop_time,
-                    )
-                    .await;
+    let controller_limit = limit.clone();
+    let controller_failures = failures.clone();
+    let controller_handle = LocalExecutorBuilder::new(controller_placement)
+        .name("controller")
+        .spawn(move || async move {
+            let (worker_pool, data_mesh, control_mesh) =
+                workers::spawn_workers(
+                    SchedulingType::Sw,
+                    worker_placement,
+                    pipeline.clone(),
+                    dispatcher.clone(),
+                    controller_failures.clone(),
+                )?;
 
-                worker_pool.join_all();
-                run_duration_and_processed_packets
+            let run = controller::run_controller(
+                task_receiver,
+                data_mesh,
+                control_mesh,
+                pipeline,
+                dispatcher,
+                controller_limit,
+                controller_failures.clone(),
+            );
+            let mut report = match run.await {
+                Ok(report) => report,
+                Err(error) => {
+                    return Err(stop_workers(
+                        worker_pool,
+                        &controller_failures,
+                        error,
+                    ))
+                }
+            };
+
+            // The workers, the generator and the controller
+            report.cores = nr_workers + 2;
+            join_workers(worker_pool, &mut report)?;
+            Ok(report)
+        })
+        .map_err(executor_failed)?;
+
+    let generator_handle = LocalExecutorBuilder::new(generator_placement)
+        .name("generator")
+        .spawn(move || async move {
+            generator(task_sender, limit).await;
+            Ok(())
+        })
+        .map_err(executor_failed)?;
+
+    let generated = join_executor(generator_handle);
+    // The generator can fail because the controller did
+    let report = join_executor(controller_handle).and_then(|report| {
+        generated?;
+        Ok(report)
+    });
+    // The failure that aborted the run is why the rest of it failed
+    match failures.take_error() {
+        Some(error) => Err(error),
+        None => report,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        admission::Admission,
+        context::StageContext,
+        failure::PanicPolicy,
+        pipeline::{
+            PipelineBuilder, StageDescriptor, StageFuture, StageOutcome,
+        },
+        types::{Msg, QueueType},
+    };
+    use futures_lite::FutureExt;
+    use std::{collections::HashMap, time::Duration};
+
+    /// Injects messages with their number as data, over four flows
+    async fn generate(
+        mut injector: Injector<u64>,
+        _limit: RunLimit,
+    ) -> Injector<u64> {
+        while !injector.limit_reached() {
+            let next = injector.injected();
+            injector.inject(Box::new(Msg::new(next, next % 4))).await;
+        }
+        injector
+    }
+
+    /// Runs `pipeline` with DSW on two workers that are not bound to cores,
+    /// until `packets` messages have been injected.
+    fn run(
+        pipeline: PipelineBuilder<u64>,
+        packets: u64,
+    ) -> Result<RunReport, Error> {
+        run_dsw(
+            PoolPlacement::Unbound(2),
+            Placement::Unbound,
+            pipeline.build().unwrap(),
+            DispatchPolicy::RoundRobin,
+            generate,
+            RunLimit::PacketCount(packets),
+        )
+    }
+
+    /// A pipeline whose sync stage panics on the messages ending in 1 and
+    /// whose async stage panics on those ending in 5.
+    fn panicking(policy: PanicPolicy) -> PipelineBuilder<u64> {
+        fn sync(msg: &mut ChannelElement<u64>, _: &mut StageContext) {
+            if msg.data % 10 == 1 {
+                panic!("sync {}", msg.data);
+            }
+        }
+        fn not_sync(
+            msg: ChannelElement<u64>,
+            _: &mut StageContext,
+        ) -> StageFuture<u64> {
+            async move {
+                if msg.data % 10 == 5 {
+                    panic!("async {}", msg.data);
+                }
+                (msg, StageOutcome::Continue)
+            }
+            .boxed_local()
+        }
+
+        Pipeline::builder()
+            .parallel(sync)
+            .stage(StageDescriptor::new_async(QueueType::Parallel, |_| {
+                not_sync
+            }))
+            .on_panic(policy)
+    }
+
+    /// Counts the messages of each flow that arrive out of order. The
+    /// messages of a flow are all processed by one worker in an atomic stage.
+    fn check_order(msg: &mut ChannelElement<u64>, ctx: &mut StageContext) {
+        let last = ctx
+            .local::<HashMap<u64, u64>>()
+            .insert(msg.flow_id, msg.data);
+        if last.is_some_and(|last| last > msg.data) {
+            ctx.count("out of order", 1);
+        }
+    }
+
+    #[test]
+    fn test_verify_core_layout() {
+        assert!(matches!(
+            verify_core_layout(&[], Some(0), None),
+            Err(Error::InvalidCoreLayout(_))
+        ));
+        assert_eq!(
+            verify_core_layout(&[1, 2], Some(3), Some(2)),
+            Err(Error::DuplicateCore(2))
+        );
+        assert_eq!(
+            verify_core_layout(&[0, u16::MAX], None, None),
+            Err(Error::CoreUnavailable(u16::MAX))
+        );
+        assert_eq!(verify_core_layout(&[0], None, None), Ok(()));
+
+        let weights = DispatchPolicy::WeightedRoundRobin(vec![1, 0]);
+        assert!(verify_dispatch_policy(&weights, 2).is_err());
+        assert!(verify_dispatch_policy(&weights, 3).is_err());
+        assert_eq!(
+            verify_dispatch_policy(&DispatchPolicy::RoundRobin, 2),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_bursts() {
+        let pipeline = Pipeline::builder()
+            .ordered(|msg: &mut ChannelElement<u64>, _: &mut StageContext| {
+                msg.data *= 2
             })
-            .unwrap();
+            .atomic(check_order)
+            .burst_size(8);
+        let report = run(pipeline, 1000).unwrap();
+
+        assert_eq!(report.injected_packets, 1000);
+        assert_eq!(report.processed_packets, 1000);
+        assert_eq!(report.stage_packets(), [1000, 1000]);
+        assert_eq!(report.stats.total_counter("out of order"), 0);
+    }
+
+    #[test]
+    fn test_admission_below_burst_size() {
+        fn stage(_: &mut ChannelElement<u64>, _: &mut StageContext) {}
+
+        // Fewer credits than a burst, so the messages waiting for the burst
+        // to fill up must be sent for the credits to come back
+        for admission in [Admission::Delay(3), Admission::Reject(3)] {
+            let pipeline = Pipeline::builder()
+                .parallel(stage)
+                .admission(admission)
+                .burst_size(8);
+            let report = run(pipeline, 100).unwrap();
+            assert_eq!(report.processed_packets, 100);
+        }
+    }
+
+    #[test]
+    fn test_drain() {
+        // Every other message does not finish the stage before the run ends
+        fn stall(
+            msg: ChannelElement<u64>,
+            _: &mut StageContext,
+        ) -> StageFuture<u64> {
+            let delay = match msg.data % 2 {
+                0 => Duration::from_secs(3600),
+                _ => Duration::ZERO,
+            };
+            async move {
+                glommio::timer::sleep(delay).await;
+                (msg, StageOutcome::Continue)
+            }
+            .boxed_local()
+        }
+
+        let pipeline = Pipeline::builder()
+            .stage(StageDescriptor::new_async(QueueType::Parallel, |_| stall))
+            .max_concurrency(4)
+            .drain_timeout(Duration::from_millis(20));
+        let report = run(pipeline, 100).unwrap();
 
-    let generator_handle =
-        LocalExecutorBuilder::new(Placement::Fixed(generator_core as usize))
-            .name("generator")
-            .spawn(move || generator(task_sender, stop_time))
-            .unwrap();
+        // The messages that were stuck in the stage, or queued behind them,
+        // are abandoned once the drain times out
+        assert!(report.abandoned_packets >= 8);
+        assert_eq!(
+            report.processed_packets + report.abandoned_packets,
+            report.injected_packets
+        );
+        assert_eq!(report.returned_packets, 100);
+        assert_eq!(report.lost_packets, 0);
+    }
+
+    #[test]
+    fn test_drop_message() {
+        let report = run(panicking(PanicPolicy::DropMessage), 100).unwrap();
+
+        // The messages of the sync stage are discarded as errors, and those
+        // of the async stage are lost with their futures
+        assert_eq!(report.stage_panics(), [10, 10]);
+        assert_eq!(report.errors.get(&(0, "stage panicked")), Some(&10));
+        assert_eq!(report.failed_packets(), 10);
+        assert_eq!(report.lost_packets, 10);
+        assert_eq!(report.processed_packets, 80);
+        assert_eq!(report.returned_packets, 100);
+    }
+
+    /// Checks that the run was aborted by one of the panics of
+    /// [`panicking`].
+    fn check_aborted(result: Result<RunReport, Error>) {
+        let Err(Error::StagePanicked(panic)) = result else {
+            panic!("The run was not aborted: {result:?}");
+        };
+        let kind = ["sync", "async"][panic.stage];
+        assert!(panic.message.starts_with(kind), "{panic}");
+    }
+
+    #[test]
+    fn test_abort() {
+        check_aborted(run(panicking(PanicPolicy::Abort), 100));
+
+        let result = run_sw(
+            PoolPlacement::Unbound(2),
+            Placement::Unbound,
+            Placement::Unbound,
+            panicking(PanicPolicy::Abort).build().unwrap(),
+            DispatchPolicy::RoundRobin,
+            |sender, limit| async move {
+                let sender = sender.connect().await;
+                let mut injected = 0;
+                while !limit.is_reached(injected) {
+                    let msg = Msg::new(injected, injected % 4);
+                    sender.send(Box::new(msg)).await.unwrap();
+                    injected += 1;
+                }
+            },
+            RunLimit::Unlimited,
+        );
+        check_aborted(result);
+    }
+
+    #[test]
+    fn test_saturated_async_stage() {
+        // The even messages go through an async stage that does not finish
+        // before the run ends, and the odd ones skip it
+        fn route(
+            msg: &mut ChannelElement<u64>,
+            _: &mut StageContext,
+        ) -> StageOutcome {
+            match msg.data % 2 {
+                0 => StageOutcome::Continue,
+                _ => StageOutcome::JumpTo(2),
+            }
+        }
+        fn stall(
+            msg: ChannelElement<u64>,
+            _: &mut StageContext,
+        ) -> StageFuture<u64> {
+            async move {
+                glommio::timer::sleep(Duration::from_secs(3600)).await;
+                (msg, StageOutcome::Continue)
+            }
+            .boxed_local()
+        }
+        fn count(_: &mut ChannelElement<u64>, ctx: &mut StageContext) {
+            ctx.count("sync", 1);
+        }
 
-    generator_handle.join().unwrap();
-    controller_handle.join().unwrap()
+        let pipeline = Pipeline::builder()
+            .parallel(route)
+            .stage(StageDescriptor::new_async(QueueType::Parallel, |_| stall))
+            .parallel(count)
+            .max_concurrency(2)
+            .drain_timeout(Duration::from_millis(20));
+        let report = run(pipeline, 1000).unwrap();
+
+        // The sync stage keeps going while the async stage has no permits
+        assert_eq!(report.stats.total_counter("sync"), 500);
+        assert_eq!(report.processed_packets, 500);
+        assert_eq!(report.abandoned_packets, 500);
+    }
+
+    #[test]
+    fn test_migration() {
+        // Flow 0 costs far more than the other flows, so the worker that owns
+        // it falls behind and moves its other flows away
+        fn skewed(msg: &mut ChannelElement<u64>, _: &mut StageContext) {
+            if msg.flow_id == 0 {
+                crate::tsc::burn(20_000);
+            }
+        }
+
+        let pipeline = Pipeline::builder()
+            .atomic(skewed)
+            .atomic(check_order)
+            .burst_size(8)
+            .drain_timeout(Duration::from_secs(60));
+        let report = run(pipeline, 20_000).unwrap();
+
+        // The messages of the migrated flows stay in order
+        assert!(report.workers.iter().any(|worker| worker.migrations > 0));
+        assert_eq!(report.processed_packets, 20_000);
+        assert_eq!(report.stats.total_counter("out of order"), 0);
+    }
+
+    #[test]
+    fn test_work_stealing() {
+        // The messages of flow 0 cost far more than the others, so the queue
+        // of the worker that round robin gives them to grows and the other
+        // worker steals from it
+        fn uneven(msg: &mut ChannelElement<u64>, ctx: &mut StageContext) {
+            if msg.flow_id == 0 {
+                crate::tsc::burn(20_000);
+            }
+            ctx.count("uneven", 1);
+        }
+
+        // The parallel stage reorders the flows by itself, so the order is
+        // checked in an atomic stage whose messages wait in the same queues
+        let pipeline = Pipeline::builder()
+            .atomic(check_order)
+            .parallel(uneven)
+            .work_stealing(true)
+            .burst_size(8)
+            .drain_timeout(Duration::from_secs(60));
+        let report = run(pipeline, 20_000).unwrap();
+
+        // Stolen messages are processed once, and the messages of the atomic
+        // stage are not stolen away from the owner of their flow
+        assert!(
+            report
+                .workers
+                .iter()
+                .map(|worker| worker.stolen)
+                .sum::<u64>()
+                > 0
+        );
+        assert_eq!(report.stats.total_counter("uneven"), 20_000);
+        assert_eq!(report.processed_packets, 20_000);
+        assert_eq!(report.stats.total_counter("out of order"), 0);
+    }
 }
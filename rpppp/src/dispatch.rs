@@ -0,0 +1,278 @@
+use rand::Rng;
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    controller::round_robin_get_next_shard,
+    flows::{FlowRoute, FlowSlot, FlowTable},
+    types::{Msg, QueueType},
+};
+
+/// How the messages of parallel and ordered stages are distributed over the
+/// workers. Atomic stages always send a flow to the same worker.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub enum DispatchPolicy {
+    /// The workers take turns, regardless of how busy they are.
+    #[default]
+    RoundRobin,
+    /// The worker with the fewest outstanding messages, that is messages sent
+    /// to it that it has not finished processing.
+    JoinShortestQueue,
+    /// The worker with the fewest outstanding messages out of two picked at
+    /// random.
+    PowerOfTwoChoices,
+    /// The workers take turns, and each worker gets its weight in messages
+    /// per turn. Needs one weight per worker, and every weight must be at
+    /// least one.
+    WeightedRoundRobin(Vec<u32>),
+}
+
+/// The number of outstanding messages of each worker. Shared by all shards,
+/// since in DSW mode the workers dispatch to each other. Also the load that
+/// decides when the workers migrate flows in DSW mode, and which worker an
+/// idle worker steals from.
+pub(crate) struct WorkerLoad {
+    outstanding: Vec<AtomicUsize>,
+    // The shard id of the worker that wants to steal from each worker, or 0
+    steal_requests: Vec<AtomicUsize>,
+}
+
+impl WorkerLoad {
+    pub(crate) fn new(nr_workers: usize) -> Arc<Self> {
+        Arc::new(WorkerLoad {
+            outstanding: (0..nr_workers).map(|_| AtomicUsize::new(0)).collect(),
+            steal_requests: (0..nr_workers)
+                .map(|_| AtomicUsize::new(0))
+                .collect(),
+        })
+    }
+
+    /// Counts a message sent to the worker with shard id `shard`.
+    pub(crate) fn sent(&self, shard: usize) {
+        // Ignores shard 0, which is the scheduler
+        self.outstanding[shard - 1].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a message that the worker with id `worker_id` is done with.
+    pub(crate) fn done(&self, worker_id: usize) {
+        self.outstanding[worker_id].fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// The number of shards, including the controller
+    pub(crate) fn nr_shards(&self) -> usize {
+        self.outstanding.len() + 1
+    }
+
+    /// The number of outstanding messages of the worker with shard id
+    /// `shard`.
+    pub(crate) fn outstanding(&self, shard: usize) -> usize {
+        self.outstanding[shard - 1].load(Ordering::Relaxed)
+    }
+
+    /// Asks the worker with shard id `victim` to send some of its queued
+    /// messages to the worker with shard id `thief`. Returns false if another
+    /// worker has already asked.
+    pub(crate) fn request_steal(&self, victim: usize, thief: usize) -> bool {
+        self.steal_requests[victim - 1]
+            .compare_exchange(0, thief, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Takes the shard id of the worker that wants to steal from the worker
+    /// with shard id `shard`, if any.
+    pub(crate) fn take_steal_request(&self, shard: usize) -> Option<usize> {
+        match self.steal_requests[shard - 1].swap(0, Ordering::AcqRel) {
+            0 => None,
+            thief => Some(thief),
+        }
+    }
+}
+
+/// Picks the worker that will process the next stage of a message, as decided
+/// by the [`DispatchPolicy`], or by the owner of the flow for atomic stages.
+/// Every shard that sends to the workers has its own dispatcher.
+#[derive(Clone)]
+pub(crate) struct Dispatcher {
+    policy: DispatchPolicy,
+    load: Arc<WorkerLoad>,
+    flows: Arc<FlowTable>,
+    nr_shards: usize,
+    prev_shard: Cell<usize>,
+    // How many more messages the previous shard gets, for weighted round robin
+    credits: Cell<u32>,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(
+        policy: DispatchPolicy,
+        load: Arc<WorkerLoad>,
+        flows: Arc<FlowTable>,
+    ) -> Self {
+        Dispatcher {
+            policy,
+            nr_shards: load.nr_shards(),
+            load,
+            flows,
+            prev_shard: Cell::new(0),
+            credits: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn load(&self) -> &Arc<WorkerLoad> {
+        &self.load
+    }
+
+    pub(crate) fn flows(&self) -> &Arc<FlowTable> {
+        &self.flows
+    }
+
+    /// Lets the shard with id `shard_id` start its round robin after itself,
+    /// so that the shards do not all start with the same worker.
+    pub(crate) fn set_shard_id(&self, shard_id: usize) {
+        self.prev_shard.set(shard_id);
+    }
+
+    /// Gets the shard that will process the next stage of `msg`, which has
+    /// the queue type `queue_type`. The message is counted as outstanding on
+    /// that worker.
+    pub(crate) fn next_shard<MsgData>(
+        &self,
+        msg: &mut Msg<MsgData>,
+        queue_type: QueueType,
+    ) -> usize {
+        let shard = match queue_type {
+            QueueType::Parallel | QueueType::Ordered => self.pick(),
+            QueueType::Atomic => {
+                let slot = self.flows.slot(msg.pipeline_index, msg.flow_id);
+                match self.flows.route(slot) {
+                    FlowRoute::Owner(shard) => {
+                        msg.flow_slot = FlowSlot::Owned(slot);
+                        shard
+                    }
+                    FlowRoute::Paused(shard) => {
+                        msg.flow_slot = FlowSlot::Paused(slot);
+                        shard
+                    }
+                }
+            }
+        };
+        self.load.sent(shard);
+        shard
+    }
+
+    /// Sends a message to `shard` without choosing, but counts it as
+    /// outstanding.
+    pub(crate) fn to_shard(&self, shard: usize) -> usize {
+        self.load.sent(shard);
+        shard
+    }
+
+    fn pick(&self) -> usize {
+        match &self.policy {
+            DispatchPolicy::RoundRobin => self.round_robin(),
+            DispatchPolicy::JoinShortestQueue => {
+                // Starts after the previous pick, so that ties are spread out
+                let start = self.round_robin();
+                (0..self.nr_shards - 1)
+                    .map(|offset| {
+                        round_robin_get_next_shard(
+                            self.nr_shards,
+                            start + offset - 1,
+                        )
+                    })
+                    .min_by_key(|&shard| self.load.outstanding(shard))
+                    .unwrap()
+            }
+            DispatchPolicy::PowerOfTwoChoices => {
+                let first = self.random_shard();
+                let second = self.random_shard();
+                if self.load.outstanding(second) < self.load.outstanding(first)
+                {
+                    second
+                } else {
+                    first
+                }
+            }
+            DispatchPolicy::WeightedRoundRobin(weights) => {
+                if self.credits.get() == 0 {
+                    let shard = self.round_robin();
+                    self.credits.set(weights[shard - 1]);
+                }
+                self.credits.set(self.credits.get() - 1);
+                self.prev_shard.get()
+            }
+        }
+    }
+
+    fn round_robin(&self) -> usize {
+        let shard =
+            round_robin_get_next_shard(self.nr_shards, self.prev_shard.get());
+        self.prev_shard.set(shard);
+        shard
+    }
+
+    fn random_shard(&self) -> usize {
+        // Ignores shard 0, which is the scheduler
+        rand::thread_rng().gen_range(1..self.nr_shards)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_dispatcher(
+        policy: DispatchPolicy,
+        load: &Arc<WorkerLoad>,
+    ) -> Dispatcher {
+        let flows = FlowTable::new(load.nr_shards(), 1);
+        Dispatcher::new(policy, load.clone(), flows)
+    }
+
+    fn pick(dispatcher: &Dispatcher) -> usize {
+        dispatcher.next_shard(&mut Msg::new((), 0), QueueType::Parallel)
+    }
+
+    fn picks(dispatcher: &Dispatcher, n: usize) -> Vec<usize> {
+        (0..n).map(|_| pick(dispatcher)).collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let dispatcher =
+            new_dispatcher(DispatchPolicy::RoundRobin, &WorkerLoad::new(3));
+        assert_eq!(picks(&dispatcher, 4), [1, 2, 3, 1]);
+
+        let weighted = DispatchPolicy::WeightedRoundRobin(vec![2, 1, 3]);
+        let dispatcher = new_dispatcher(weighted, &WorkerLoad::new(3));
+        assert_eq!(picks(&dispatcher, 7), [1, 1, 2, 3, 3, 3, 1]);
+    }
+
+    #[test]
+    fn test_load_aware() {
+        let load = WorkerLoad::new(3);
+        let dispatcher =
+            new_dispatcher(DispatchPolicy::JoinShortestQueue, &load);
+        assert_eq!(picks(&dispatcher, 3), [1, 2, 3]);
+        // Worker 1, which is shard 2, has finished its message
+        load.done(1);
+        assert_eq!(picks(&dispatcher, 1), [2]);
+
+        let load = WorkerLoad::new(2);
+        let dispatcher =
+            new_dispatcher(DispatchPolicy::PowerOfTwoChoices, &load);
+        for _ in 0..100 {
+            if pick(&dispatcher) == 1 {
+                load.done(0);
+            }
+        }
+        // Worker 0 never has anything outstanding, so it is always picked
+        // when it is one of the two choices
+        assert!(load.outstanding(2) < 50);
+    }
+}
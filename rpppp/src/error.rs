@@ -0,0 +1,90 @@
+use std::fmt;
+
+use crate::{failure::StagePanic, types::ControlMessage};
+
+/// Errors that end a run, or keep it from starting.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The cores of the run can not be used, for example because there are
+    /// no worker cores.
+    InvalidCoreLayout(&'static str),
+    /// The core is used more than once, by workers, the generator or the
+    /// controller.
+    DuplicateCore(u16),
+    /// The core is not online, or the online cores could not be found.
+    CoreUnavailable(u16),
+    /// The dispatch policy can not be used with the workers of the run.
+    InvalidDispatchPolicy(&'static str),
+    /// An executor of the run could not be started, or its thread panicked
+    /// outside of the stages.
+    ExecutorFailed(String),
+    /// A shard could not join a channel mesh.
+    MeshJoinFailed(String),
+    /// A channel between the shards was closed while the run still used it.
+    ChannelClosed,
+    /// The controller did not get the id it must have in a channel mesh.
+    ControllerIdMismatch {
+        mesh: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// The event vectors of an aggregator can not hold any packet.
+    ZeroVectorSize,
+    /// A shard got a control message that the shutdown protocol does not
+    /// allow at that point.
+    UnexpectedControlMessage(ControlMessage),
+    /// A stage panicked and aborted the run.
+    StagePanicked(StagePanic),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidCoreLayout(reason) => {
+                write!(f, "invalid core layout: {reason}")
+            }
+            Error::DuplicateCore(core) => {
+                write!(f, "core {core} is used more than once")
+            }
+            Error::CoreUnavailable(core) => {
+                write!(f, "core {core} is not available")
+            }
+            Error::InvalidDispatchPolicy(reason) => {
+                write!(f, "invalid dispatch policy: {reason}")
+            }
+            Error::ExecutorFailed(reason) => {
+                write!(f, "an executor failed: {reason}")
+            }
+            Error::MeshJoinFailed(reason) => {
+                write!(f, "could not join the channel mesh: {reason}")
+            }
+            Error::ChannelClosed => {
+                write!(f, "a channel between the shards was closed")
+            }
+            Error::ControllerIdMismatch {
+                mesh,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "the controller has id {actual} in the {mesh} mesh instead \
+                 of {expected}"
+            ),
+            Error::ZeroVectorSize => {
+                write!(f, "event vectors must fit at least one packet")
+            }
+            Error::UnexpectedControlMessage(message) => {
+                write!(f, "unexpected control message {message:?}")
+            }
+            Error::StagePanicked(panic) => panic.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<StagePanic> for Error {
+    fn from(panic: StagePanic) -> Self {
+        Error::StagePanicked(panic)
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    any::Any,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    error::Error,
+    flows::FlowSlot,
+    limit::{CancellationToken, RunLimit},
+    pipeline::StageOutcome,
+    types::Msg,
+};
+
+/// What a message that a sync stage panicked on is discarded as. It is
+/// counted with the other errors of the stage.
+pub const PANIC_OUTCOME: StageOutcome = StageOutcome::Error("stage panicked");
+
+/// What happens to the run when a stage panics. The panic is always caught,
+/// so that the worker keeps running, and counted per stage.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PanicPolicy {
+    /// The message is discarded and the run goes on. A message that an async
+    /// stage panicked on is lost, since the future owned it.
+    DropMessage,
+    /// The message is discarded, no more messages are injected and the
+    /// messages in the pipeline are abandoned. The run then ends with
+    /// [`Error::StagePanicked`].
+    #[default]
+    Abort,
+}
+
+/// The first panic of a stage in a run that was aborted by it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StagePanic {
+    /// The index of the stage that panicked.
+    pub stage: usize,
+    /// The id of the worker that ran the stage.
+    pub worker_id: usize,
+    /// The message that the stage panicked with, if it was a string.
+    pub message: String,
+}
+
+impl StagePanic {
+    pub(crate) fn new(
+        stage: usize,
+        worker_id: usize,
+        payload: Box<dyn Any + Send>,
+    ) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "Box<dyn Any>".to_string(),
+            },
+        };
+        StagePanic {
+            stage,
+            worker_id,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for StagePanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "stage {} panicked on worker {}: {}",
+            self.stage, self.worker_id, self.message
+        )
+    }
+}
+
+impl std::error::Error for StagePanic {}
+
+/// What the controller needs to know about a message that was lost in an
+/// async stage that panicked, to count it as having left the pipeline. Also
+/// used for the messages that were abandoned in async stages that did not
+/// finish before the drain timeout.
+pub(crate) struct LostMessage {
+    pub(crate) flow_slot: FlowSlot,
+    pub(crate) ordered_stage: Option<usize>,
+    pub(crate) flow_id: u64,
+    pub(crate) seq: u64,
+    pub(crate) abandoned: bool,
+}
+
+impl LostMessage {
+    pub(crate) fn of<MsgData>(msg: &Msg<MsgData>) -> Self {
+        LostMessage {
+            flow_slot: msg.flow_slot,
+            ordered_stage: msg.ordered_stage,
+            flow_id: msg.flow_id,
+            seq: msg.seq,
+            abandoned: false,
+        }
+    }
+}
+
+/// The panics and other failures of a run, shared by the workers and the
+/// controller.
+pub(crate) struct Failures {
+    policy: PanicPolicy,
+    // Cancelled when a failure aborts the run, which is part of the run limit
+    abort: CancellationToken,
+    // The first failure that aborted the run
+    error: Mutex<Option<Error>>,
+    // Lost messages that the controller has not counted yet
+    lost: Mutex<Vec<LostMessage>>,
+}
+
+impl Failures {
+    pub(crate) fn new(policy: PanicPolicy) -> Arc<Self> {
+        Arc::new(Failures {
+            policy,
+            abort: CancellationToken::new(),
+            error: Mutex::new(None),
+            lost: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Extends `limit` so that it is also reached once the run is aborted.
+    pub(crate) fn limit(&self, limit: RunLimit) -> RunLimit {
+        RunLimit::Any(vec![limit, RunLimit::Until(self.abort.clone())])
+    }
+
+    /// Records that a stage panicked, which aborts the run with
+    /// [`PanicPolicy::Abort`].
+    pub(crate) fn panicked(&self, panic: StagePanic) {
+        if self.policy == PanicPolicy::Abort {
+            self.fail(panic.into());
+        }
+    }
+
+    /// Aborts the run because of `error`, unless it has already failed.
+    pub(crate) fn fail(&self, error: Error) {
+        self.error.lock().unwrap().get_or_insert(error);
+        self.abort.cancel();
+    }
+
+    pub(crate) fn aborted(&self) -> bool {
+        self.abort.is_cancelled()
+    }
+
+    /// Gets the failure that aborted the run, if any.
+    pub(crate) fn take_error(&self) -> Option<Error> {
+        self.error.lock().unwrap().take()
+    }
+
+    pub(crate) fn lose(&self, lost: LostMessage) {
+        self.lost.lock().unwrap().push(lost);
+    }
+
+    /// Takes the lost messages that the controller has not counted yet.
+    pub(crate) fn take_lost(&self) -> Vec<LostMessage> {
+        std::mem::take(&mut self.lost.lock().unwrap())
+    }
+}
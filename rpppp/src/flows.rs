@@ -0,0 +1,202 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::controller::flow_get_shard;
+
+/// The number of flow groups per stage. Flows are owned and migrated between
+/// the workers in groups, like the flow hash buckets of DPDK DSW.
+pub(crate) const FLOW_GROUPS: usize = 256;
+
+/// How a message is counted in the [`FlowTable`] while it is on its way to,
+/// or waiting at, the worker running an atomic stage.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) enum FlowSlot {
+    /// Not in an atomic stage, or not counted
+    #[default]
+    None,
+    /// Sent to the owner of the flow group with the provided index
+    Owned(usize),
+    /// Sent to the worker that the flow group with the provided index is
+    /// migrating to, which holds it until the migration is done
+    Paused(usize),
+}
+
+/// Where to send a message of an atomic stage.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum FlowRoute {
+    Owner(usize),
+    Paused(usize),
+}
+
+// Every entry is one word, so that it can be changed atomically: the shard id
+// of the owner, the shard id of the worker it migrates to or 0, and the number
+// of messages sent to the owner that it has not finished.
+const OWNER_BITS: u64 = 0xffff;
+const TARGET_SHIFT: u32 = 16;
+const IN_FLIGHT_SHIFT: u32 = 32;
+
+fn owner(entry: u64) -> usize {
+    (entry & OWNER_BITS) as usize
+}
+
+fn target(entry: u64) -> usize {
+    ((entry >> TARGET_SHIFT) & OWNER_BITS) as usize
+}
+
+fn in_flight(entry: u64) -> u64 {
+    entry >> IN_FLIGHT_SHIFT
+}
+
+fn entry(owner: usize, target: usize, in_flight: u64) -> u64 {
+    owner as u64
+        | (target as u64) << TARGET_SHIFT
+        | in_flight << IN_FLIGHT_SHIFT
+}
+
+/// Which worker owns each flow group of each stage, shared by all shards.
+///
+/// A flow group is migrated by its owner in two steps, so that the messages
+/// of a flow are still processed by one worker at a time and in the order they
+/// were sent. First the group is paused, and the messages sent to it go to the
+/// new owner, which holds them. Once the messages that were sent to the old
+/// owner have been processed and have arrived at their next shard, the new
+/// owner takes over and processes the held messages first. Waiting for them to
+/// arrive keeps them ahead of the messages the new owner sends on, since they
+/// use different channels.
+pub(crate) struct FlowTable {
+    entries: Vec<AtomicU64>,
+}
+
+impl FlowTable {
+    pub(crate) fn new(nr_shards: usize, nr_stages: usize) -> Arc<Self> {
+        let entries = (0..nr_stages * FLOW_GROUPS)
+            .map(|slot| {
+                let shard = flow_get_shard(
+                    nr_shards,
+                    (slot % FLOW_GROUPS) as u64,
+                    slot / FLOW_GROUPS,
+                );
+                AtomicU64::new(entry(shard, 0, 0))
+            })
+            .collect();
+        Arc::new(FlowTable { entries })
+    }
+
+    /// The index of the flow group of `flow_id` at stage `stage`.
+    pub(crate) fn slot(&self, stage: usize, flow_id: u64) -> usize {
+        let group = (flow_id.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32)
+            as usize
+            % FLOW_GROUPS;
+        stage * FLOW_GROUPS + group
+    }
+
+    /// Gets where to send a message of the flow group `slot`. A message sent
+    /// to the owner is counted until [`FlowTable::done`] is called.
+    pub(crate) fn route(&self, slot: usize) -> FlowRoute {
+        let mut current = self.entries[slot].load(Ordering::Acquire);
+        loop {
+            if target(current) != 0 {
+                return FlowRoute::Paused(target(current));
+            }
+            match self.entries[slot].compare_exchange_weak(
+                current,
+                current + (1 << IN_FLIGHT_SHIFT),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return FlowRoute::Owner(owner(current)),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Counts that a message of the flow group `slot` sent to the owner has
+    /// been processed and has arrived at its next shard. Returns true if that
+    /// finished a migration.
+    pub(crate) fn done(&self, slot: usize) -> bool {
+        let mut current = self.entries[slot].load(Ordering::Acquire);
+        loop {
+            let (next, migrated) = match in_flight(current) {
+                1 if target(current) != 0 => {
+                    (entry(target(current), 0, 0), true)
+                }
+                _ => (current - (1 << IN_FLIGHT_SHIFT), false),
+            };
+            match self.entries[slot].compare_exchange_weak(
+                current,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return migrated,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Starts migrating the flow group `slot` from `from` to `to`, if `from`
+    /// owns it and it is not already migrating. Returns [`None`] if the
+    /// migration could not start, and otherwise if it is already done.
+    pub(crate) fn migrate(
+        &self,
+        slot: usize,
+        from: usize,
+        to: usize,
+    ) -> Option<bool> {
+        let current = self.entries[slot].load(Ordering::Acquire);
+        if owner(current) != from || target(current) != 0 {
+            return None;
+        }
+        let (next, migrated) = match in_flight(current) {
+            0 => (entry(to, 0, 0), true),
+            _ => (current | (to as u64) << TARGET_SHIFT, false),
+        };
+        self.entries[slot]
+            .compare_exchange(
+                current,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .ok()
+            .map(|_| migrated)
+    }
+
+    /// Checks if `shard` owns the flow group `slot` and it is not migrating.
+    pub(crate) fn is_settled(&self, slot: usize, shard: usize) -> bool {
+        let current = self.entries[slot].load(Ordering::Acquire);
+        owner(current) == shard && target(current) == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let table = FlowTable::new(4, 2);
+        let slot = table.slot(1, 7);
+        assert_eq!(slot / FLOW_GROUPS, 1);
+        let FlowRoute::Owner(from) = table.route(slot) else {
+            panic!("expected the owner");
+        };
+        let to = from % 3 + 1;
+        assert_eq!(table.migrate(slot, to, from), None);
+
+        // The message sent to the old owner must be done first
+        assert_eq!(table.migrate(slot, from, to), Some(false));
+        assert_eq!(table.route(slot), FlowRoute::Paused(to));
+        assert!(!table.is_settled(slot, to));
+        assert!(table.done(slot));
+        assert!(table.is_settled(slot, to));
+        assert_eq!(table.route(slot), FlowRoute::Owner(to));
+        assert!(!table.done(slot));
+
+        // Nothing in flight, so the migration is done at once
+        assert_eq!(table.migrate(slot, to, from), Some(true));
+        assert_eq!(table.route(slot), FlowRoute::Owner(from));
+    }
+}
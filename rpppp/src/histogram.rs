@@ -1,7 +1,11 @@
+use std::fmt;
+
 /// Store how many times an event has happened. [`N`] is the size of a
 /// contiguous array.
+#[derive(Clone)]
 pub struct Histogram<const N: usize> {
-    content: [usize; N],
+    // Boxed so that large histograms can be created on any thread
+    content: Box<[usize; N]>,
     content_overflow: Vec<usize>,
 }
 
@@ -14,7 +18,7 @@ impl<const N: usize> Default for Histogram<N> {
 impl<const N: usize> Histogram<N> {
     pub fn new() -> Self {
         Self {
-            content: [0; N],
+            content: vec![0; N].into_boxed_slice().try_into().unwrap(),
             content_overflow: Vec::with_capacity(64),
         }
     }
@@ -97,6 +101,11 @@ impl<const N: usize> Histogram<N> {
         }
     }
 
+    /// The number of values in the histogram.
+    pub fn count(&self) -> usize {
+        self.content.iter().sum::<usize>() + self.content_overflow.len()
+    }
+
     /// The largest value in the histogram.
     pub fn max_value(&self) -> usize {
         if let Some(max_value) = self.content_overflow.iter().max() {
@@ -111,6 +120,16 @@ impl<const N: usize> Histogram<N> {
     }
 }
 
+// The contents are too large to print
+impl<const N: usize> fmt::Debug for Histogram<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Histogram")
+            .field("count", &self.count())
+            .field("max_value", &self.max_value())
+            .finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
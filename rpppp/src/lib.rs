@@ -1,9 +1,22 @@
-#![feature(get_mut_unchecked)]
-
+pub mod admission;
+pub mod context;
 pub mod core;
+pub mod dispatch;
+pub mod error;
+pub mod failure;
 pub mod histogram;
+pub mod limit;
+pub mod pipeline;
+pub mod pool;
+pub mod priority;
+pub mod reorder;
 pub mod tsc;
 pub mod types;
+pub mod vector;
 
 mod controller;
+mod flows;
+mod mesh;
 mod workers;
+
+pub use crate::error::Error;
@@ -0,0 +1,107 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use signal_hook::{consts::SIGINT, flag};
+
+/// When the generator stops injecting new messages, after which the messages
+/// in the pipeline are drained. Both the generator and the controller check
+/// it with [`RunLimit::is_reached`].
+#[derive(Clone, Debug)]
+pub enum RunLimit {
+    /// Messages are injected until the instant.
+    Deadline(Instant),
+    /// The number of messages that are injected, like `-n` of the C
+    /// pipeline.
+    PacketCount(u64),
+    /// Messages are injected until the token is cancelled, for example by
+    /// Ctrl-C with [`CancellationToken::on_sigint`].
+    Until(CancellationToken),
+    /// Messages are injected until any of the limits is reached.
+    Any(Vec<RunLimit>),
+    /// Messages are injected until the generator stops by itself.
+    Unlimited,
+}
+
+impl RunLimit {
+    /// Injects messages for `duration` from now.
+    pub fn after(duration: Duration) -> Self {
+        RunLimit::Deadline(Instant::now() + duration)
+    }
+
+    /// Checks if no more messages should be injected, when `injected`
+    /// messages have been.
+    pub fn is_reached(&self, injected: u64) -> bool {
+        match self {
+            RunLimit::Deadline(deadline) => Instant::now() >= *deadline,
+            RunLimit::PacketCount(count) => injected >= *count,
+            RunLimit::Until(token) => token.is_cancelled(),
+            RunLimit::Any(limits) => {
+                limits.iter().any(|limit| limit.is_reached(injected))
+            }
+            RunLimit::Unlimited => false,
+        }
+    }
+}
+
+/// A flag that stops a run with [`RunLimit::Until`], shared by all its
+/// clones.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a token that is cancelled by the first SIGINT, so that Ctrl-C
+    /// ends the run cleanly. A second SIGINT exits the process right away.
+    pub fn on_sigint() -> io::Result<Self> {
+        let token = Self::new();
+        // Registered first, so that it sees if the token was already
+        // cancelled
+        flag::register_conditional_shutdown(SIGINT, 1, token.0.clone())?;
+        flag::register(SIGINT, token.0.clone())?;
+        Ok(token)
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_reached() {
+        assert!(!RunLimit::PacketCount(3).is_reached(2));
+        assert!(RunLimit::PacketCount(3).is_reached(3));
+        assert!(!RunLimit::after(Duration::from_secs(60)).is_reached(0));
+        assert!(RunLimit::Deadline(Instant::now()).is_reached(0));
+        assert!(!RunLimit::Unlimited.is_reached(u64::MAX));
+
+        let token = CancellationToken::new();
+        let limit = RunLimit::Until(token.clone());
+        assert!(!limit.is_reached(0));
+        token.cancel();
+        assert!(limit.is_reached(0));
+
+        let limit = RunLimit::Any(vec![
+            RunLimit::PacketCount(3),
+            RunLimit::Until(CancellationToken::new()),
+        ]);
+        assert!(!limit.is_reached(2));
+        assert!(limit.is_reached(3));
+    }
+}
@@ -0,0 +1,154 @@
+use std::rc::Rc;
+
+use glommio::{
+    channels::{
+        channel_mesh::{FullMesh, Receivers, Senders},
+        local_channel::{self, LocalReceiver, LocalSender},
+        sharding::Handler,
+    },
+    task::JoinHandle,
+};
+
+use crate::error::Error;
+
+/// The end of a full mesh that belongs to this shard, like the `Sharded` of
+/// glommio but without owning the handler. The handlers can therefore keep
+/// a clone and send with it, while the received messages are handed to them
+/// by the tasks of [`MeshReceivers::handle_with`].
+pub(crate) struct MeshShard<T: Send + 'static> {
+    inner: Rc<Inner<T>>,
+}
+
+struct Inner<T: Send + 'static> {
+    senders: Senders<T>,
+    // The mesh has no channel from a shard to itself. None ends the task
+    // handling these messages.
+    local: LocalSender<Option<T>>,
+}
+
+// Derived Clone would require T to be Clone
+impl<T: Send> Clone for MeshShard<T> {
+    fn clone(&self) -> Self {
+        MeshShard {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// The messages received by this shard, until they are handed to a handler.
+pub(crate) struct MeshReceivers<T: Send + 'static> {
+    receivers: Receivers<T>,
+    local: LocalReceiver<Option<T>>,
+}
+
+/// The tasks handing the received messages to the handler.
+pub(crate) struct MeshConsumers {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send> MeshShard<T> {
+    /// Joins `mesh`. The received messages are not handled until
+    /// [`MeshReceivers::handle_with`] is called.
+    pub(crate) async fn join(
+        mesh: FullMesh<T>,
+    ) -> Result<(Self, MeshReceivers<T>), Error> {
+        let (senders, receivers) = mesh
+            .join()
+            .await
+            .map_err(|error| Error::MeshJoinFailed(error.to_string()))?;
+        let (local, local_receiver) = local_channel::new_unbounded();
+        let shard = MeshShard {
+            inner: Rc::new(Inner { senders, local }),
+        };
+        let receivers = MeshReceivers {
+            receivers,
+            local: local_receiver,
+        };
+        Ok((shard, receivers))
+    }
+
+    pub(crate) fn shard_id(&self) -> usize {
+        self.inner.senders.peer_id()
+    }
+
+    pub(crate) fn nr_shards(&self) -> usize {
+        self.inner.senders.nr_consumers()
+    }
+
+    /// Sends `message` to the shard with id `dst_shard`, which may be this
+    /// shard. Fails if the channel to the shard is closed.
+    pub(crate) async fn send_to(
+        &self,
+        dst_shard: usize,
+        message: T,
+    ) -> Result<(), Error> {
+        if dst_shard == self.shard_id() {
+            // Never full, since it is unbounded
+            self.inner
+                .local
+                .try_send(Some(message))
+                .map_err(|_| Error::ChannelClosed)
+        } else {
+            self.inner
+                .senders
+                .send_to(dst_shard, message)
+                .await
+                .map_err(|_| Error::ChannelClosed)
+        }
+    }
+
+    /// Stops sending. The other shards stop receiving from this shard, and
+    /// this shard stops handling its own messages. Fails if this shard had
+    /// already stopped handling them.
+    pub(crate) fn close(&self) -> Result<(), Error> {
+        self.inner.senders.close();
+        self.inner
+            .local
+            .try_send(None)
+            .map_err(|_| Error::ChannelClosed)
+    }
+}
+
+impl<T: Send> MeshReceivers<T> {
+    /// Spawns a task per shard sending to this shard, which hands the
+    /// received messages to `handler` one at a time.
+    pub(crate) fn handle_with<H: Handler<T> + 'static>(
+        mut self,
+        handler: H,
+    ) -> MeshConsumers {
+        let shard_id = self.receivers.peer_id();
+        let mut tasks: Vec<_> = self
+            .receivers
+            .streams()
+            .into_iter()
+            .map(|(src_shard, stream)| {
+                let handler = handler.clone();
+                glommio::spawn_local(async move {
+                    while let Some(message) = stream.recv().await {
+                        handler.handle(message, src_shard, shard_id).await;
+                    }
+                })
+                .detach()
+            })
+            .collect();
+        let local = self.local;
+        tasks.push(
+            glommio::spawn_local(async move {
+                while let Some(Some(message)) = local.recv().await {
+                    handler.handle(message, shard_id, shard_id).await;
+                }
+            })
+            .detach(),
+        );
+        MeshConsumers { tasks }
+    }
+}
+
+impl MeshConsumers {
+    /// Waits until all shards have closed their ends of the mesh.
+    pub(crate) async fn join(self) {
+        for task in self.tasks {
+            task.await;
+        }
+    }
+}
@@ -0,0 +1,1082 @@
+use std::{
+    collections::HashMap, fmt, future::Future, pin::Pin, sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    admission::Admission,
+    context::StageContext,
+    failure::PanicPolicy,
+    pool::MsgPool,
+    priority::{PriorityMode, DEFAULT_PRIORITY, PRIORITY_CLASSES},
+    types::{ChannelElement, Msg, QueueType},
+};
+
+/// What happens to a message after a stage has processed it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StageOutcome {
+    /// The message continues to the first successor of the stage.
+    Continue,
+    /// The message continues to the successor of the stage with the provided
+    /// position in its list of successors.
+    Branch(usize),
+    /// The message is discarded, for example by a firewall. Counted per stage
+    /// and reason.
+    Drop(&'static str),
+    /// The message continues to the stage with the provided index, which must
+    /// be downstream of the stage, so that it can be reached by following the
+    /// successors. Jumping to the length of the pipeline completes the
+    /// message.
+    JumpTo(usize),
+    /// The message skips the rest of the pipeline and counts as processed.
+    Complete,
+    /// The message could not be processed and is discarded. Counted per stage
+    /// and reason.
+    Error(&'static str),
+    /// A copy of the message continues to every successor of the stage.
+    FanOut,
+    /// A copy of the message is sent to every worker, which all run the first
+    /// successor of the stage regardless of its queue type.
+    Broadcast,
+}
+
+/// How a message is copied when it fans out. The copies are made by the
+/// controller, which keeps track of how many messages there are.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum FanOut {
+    Successors,
+    Workers,
+}
+
+/// Stages returning nothing always continue to the next stage.
+impl From<()> for StageOutcome {
+    fn from(_: ()) -> Self {
+        StageOutcome::Continue
+    }
+}
+
+/// A stage in the pipeline. Every worker gets its own instance of each stage,
+/// so a stage can keep state, like configuration or statistics, without any
+/// synchronization. Closures taking the message and the [`StageContext`] and
+/// returning a [`StageOutcome`] or nothing are also stages.
+pub trait Stage<MsgData>: 'static {
+    /// Runs the stage on `msg`. `ctx` belongs to the worker running the stage.
+    fn process(
+        &mut self,
+        msg: &mut ChannelElement<MsgData>,
+        ctx: &mut StageContext,
+    ) -> StageOutcome;
+}
+
+impl<MsgData, F, R> Stage<MsgData> for F
+where
+    F: FnMut(&mut ChannelElement<MsgData>, &mut StageContext) -> R + 'static,
+    R: Into<StageOutcome>,
+{
+    fn process(
+        &mut self,
+        msg: &mut ChannelElement<MsgData>,
+        ctx: &mut StageContext,
+    ) -> StageOutcome {
+        self(msg, ctx).into()
+    }
+}
+
+/// The future returned by an [`AsyncStage`], resolving to the processed
+/// message and what happens to it next.
+pub type StageFuture<MsgData> =
+    Pin<Box<dyn Future<Output = (ChannelElement<MsgData>, StageOutcome)>>>;
+
+/// A stage that can await, for example I/O, timers or other services on the
+/// worker. The worker keeps processing other messages while the future is
+/// pending, up to the concurrency limit of the pipeline. Messages can
+/// therefore finish the stage out of order, which an ordered stage corrects
+/// but an atomic stage does not. Closures taking the message and the
+/// [`StageContext`] and returning a future are also async stages.
+///
+/// The context can only be used before the future is returned, since other
+/// messages use it while the future is pending.
+pub trait AsyncStage<MsgData>: 'static {
+    /// Starts running the stage on `msg`. `ctx` belongs to the worker running
+    /// the stage.
+    fn process(
+        &self,
+        msg: ChannelElement<MsgData>,
+        ctx: &mut StageContext,
+    ) -> StageFuture<MsgData>;
+}
+
+impl<MsgData: 'static, F, Fut> AsyncStage<MsgData> for F
+where
+    F: Fn(ChannelElement<MsgData>, &mut StageContext) -> Fut + 'static,
+    Fut: Future<Output = (ChannelElement<MsgData>, StageOutcome)> + 'static,
+{
+    fn process(
+        &self,
+        msg: ChannelElement<MsgData>,
+        ctx: &mut StageContext,
+    ) -> StageFuture<MsgData> {
+        Box::pin(self(msg, ctx))
+    }
+}
+
+/// The instance of a stage on a worker
+pub(crate) enum StageInstance<MsgData> {
+    Sync(Box<dyn Stage<MsgData>>),
+    Async(Box<dyn AsyncStage<MsgData>>),
+}
+
+/// Creates the instance of a stage for the worker with the provided id.
+type StageFactory<MsgData> =
+    Arc<dyn Fn(usize) -> StageInstance<MsgData> + Send + Sync>;
+
+/// A stage in a pipeline. Contains the factory creating the stage on each
+/// worker, how the messages are scheduled to the workers running it, its
+/// priority class, and the stages the messages can continue to.
+pub struct StageDescriptor<MsgData: 'static> {
+    factory: StageFactory<MsgData>,
+    pub queue_type: QueueType,
+    pub priority: u8,
+    name: Option<&'static str>,
+    successors: Option<Vec<&'static str>>,
+}
+
+// Derived Clone would require MsgData to be Clone
+impl<MsgData> Clone for StageDescriptor<MsgData> {
+    fn clone(&self) -> Self {
+        StageDescriptor {
+            factory: self.factory.clone(),
+            queue_type: self.queue_type,
+            priority: self.priority,
+            name: self.name,
+            successors: self.successors.clone(),
+        }
+    }
+}
+
+impl<MsgData> StageDescriptor<MsgData> {
+    /// Creates a stage where each worker gets the instance returned by
+    /// `factory` when called with the id of the worker.
+    pub fn new<S, F>(queue_type: QueueType, factory: F) -> Self
+    where
+        S: Stage<MsgData>,
+        F: Fn(usize) -> S + Send + Sync + 'static,
+    {
+        StageDescriptor {
+            factory: Arc::new(move |worker_id| {
+                StageInstance::Sync(Box::new(factory(worker_id)))
+            }),
+            queue_type,
+            priority: DEFAULT_PRIORITY,
+            name: None,
+            successors: None,
+        }
+    }
+
+    /// Creates an async stage where each worker gets the instance returned by
+    /// `factory` when called with the id of the worker.
+    pub fn new_async<S, F>(queue_type: QueueType, factory: F) -> Self
+    where
+        S: AsyncStage<MsgData>,
+        F: Fn(usize) -> S + Send + Sync + 'static,
+    {
+        StageDescriptor {
+            factory: Arc::new(move |worker_id| {
+                StageInstance::Async(Box::new(factory(worker_id)))
+            }),
+            queue_type,
+            priority: DEFAULT_PRIORITY,
+            name: None,
+            successors: None,
+        }
+    }
+
+    /// Creates a stage where each worker gets a clone of `stage`.
+    pub fn from_stage<S>(queue_type: QueueType, stage: S) -> Self
+    where
+        S: Stage<MsgData> + Clone + Send + Sync,
+    {
+        Self::new(queue_type, move |_| stage.clone())
+    }
+
+    /// Creates an async stage where each worker gets a clone of `stage`.
+    pub fn from_async_stage<S>(queue_type: QueueType, stage: S) -> Self
+    where
+        S: AsyncStage<MsgData> + Clone + Send + Sync,
+    {
+        Self::new_async(queue_type, move |_| stage.clone())
+    }
+
+    pub fn parallel<S>(stage: S) -> Self
+    where
+        S: Stage<MsgData> + Clone + Send + Sync,
+    {
+        Self::from_stage(QueueType::Parallel, stage)
+    }
+
+    pub fn atomic<S>(stage: S) -> Self
+    where
+        S: Stage<MsgData> + Clone + Send + Sync,
+    {
+        Self::from_stage(QueueType::Atomic, stage)
+    }
+
+    pub fn ordered<S>(stage: S) -> Self
+    where
+        S: Stage<MsgData> + Clone + Send + Sync,
+    {
+        Self::from_stage(QueueType::Ordered, stage)
+    }
+
+    /// Names the stage, so that other stages can name it as a successor.
+    pub fn named(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Sets the names of the stages that messages can continue to after this
+    /// one. The first is where [`StageOutcome::Continue`] goes, and
+    /// [`StageOutcome::Branch`] picks one by position. Without successors, the
+    /// messages continue to the next stage added to the pipeline, and with an
+    /// empty list the messages are done after this stage.
+    pub fn successors(mut self, successors: &[&'static str]) -> Self {
+        self.successors = Some(successors.to_vec());
+        self
+    }
+
+    /// Sets the priority class of the messages waiting for this stage, where 0
+    /// is the highest. Must be less than [`PRIORITY_CLASSES`].
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Creates the instance of the stage for a worker
+    pub(crate) fn instantiate(
+        &self,
+        worker_id: usize,
+    ) -> StageInstance<MsgData> {
+        (self.factory)(worker_id)
+    }
+}
+
+/// The default number of messages each worker runs concurrently in async
+/// stages.
+pub const DEFAULT_MAX_CONCURRENCY: usize = 64;
+
+/// The default largest number of messages moved per send over the data mesh,
+/// and processed by a worker before it sends them on.
+pub const DEFAULT_BURST_SIZE: usize = 1;
+
+/// The default longest time the messages in the pipeline get to finish once
+/// no new messages are injected.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Errors found when building a pipeline.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PipelineError {
+    /// The pipeline has no stages.
+    Empty,
+    /// The concurrency limit is zero, so async stages could never run.
+    ZeroConcurrency,
+    /// A successor names a stage that does not exist.
+    UnknownStage(&'static str),
+    /// More than one stage has the name.
+    DuplicateName(&'static str),
+    /// The stage with the index can be reached from itself, so messages could
+    /// go around forever.
+    Cycle(usize),
+    /// The stage with the index has a priority class that does not exist.
+    InvalidPriority(usize),
+    /// A priority class has weight zero, so it would never be served.
+    ZeroWeight,
+    /// The admission limit is zero, so no message could ever be injected.
+    ZeroAdmissionLimit,
+    /// The burst size is zero, so no message could ever be sent.
+    ZeroBurstSize,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::Empty => write!(f, "the pipeline has no stages"),
+            PipelineError::ZeroConcurrency => {
+                write!(f, "the concurrency limit must be at least one")
+            }
+            PipelineError::UnknownStage(name) => {
+                write!(f, "there is no stage named {name}")
+            }
+            PipelineError::DuplicateName(name) => {
+                write!(f, "more than one stage is named {name}")
+            }
+            PipelineError::Cycle(stage) => {
+                write!(f, "stage {stage} is part of a cycle")
+            }
+            PipelineError::InvalidPriority(stage) => write!(
+                f,
+                "stage {stage} must have a priority less than \
+                 {PRIORITY_CLASSES}"
+            ),
+            PipelineError::ZeroWeight => {
+                write!(f, "the priority weights must be at least one")
+            }
+            PipelineError::ZeroAdmissionLimit => {
+                write!(f, "the admission limit must be at least one")
+            }
+            PipelineError::ZeroBurstSize => {
+                write!(f, "the burst size must be at least one")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// The stages that are run on each message. The stages form a directed
+/// acyclic graph, where every message starts at the first stage and follows
+/// the edges to the successors of each stage. Created with a
+/// [`PipelineBuilder`].
+pub struct Pipeline<MsgData: 'static> {
+    stages: Vec<StageDescriptor<MsgData>>,
+    successors: Vec<Vec<usize>>,
+    // Whether each stage can be reached from each stage, which is where the
+    // stages can jump to
+    downstream: Vec<Vec<bool>>,
+    max_concurrency: usize,
+    priority_mode: PriorityMode,
+    work_stealing: bool,
+    admission: Admission,
+    burst_size: usize,
+    message_pool: Option<MsgPool<MsgData>>,
+    drain_timeout: Duration,
+    panic_policy: PanicPolicy,
+}
+
+impl<MsgData> Pipeline<MsgData> {
+    pub fn builder() -> PipelineBuilder<MsgData> {
+        PipelineBuilder::new()
+    }
+
+    /// Gets the stage at `index`, or [`None`] if the pipeline is done.
+    pub fn stage(&self, index: usize) -> Option<&StageDescriptor<MsgData>> {
+        self.stages.get(index)
+    }
+
+    /// The index of the stage named `name`.
+    pub fn stage_index(&self, name: &str) -> Option<usize> {
+        self.stages
+            .iter()
+            .position(|stage| stage.name == Some(name))
+    }
+
+    /// The indices of the stages that messages can continue to after the
+    /// stage at `index`. Empty if the messages are done after the stage.
+    pub fn successors(&self, index: usize) -> &[usize] {
+        &self.successors[index]
+    }
+
+    /// Moves `msg` on from the stage it has just been through, as decided by
+    /// `outcome`. A message that is done gets the length of the pipeline as
+    /// index. A discarded message, or a message that fans out, keeps the index
+    /// of the stage it has been through.
+    pub(crate) fn advance(
+        &self,
+        msg: &mut Msg<MsgData>,
+        outcome: StageOutcome,
+    ) {
+        let successors = &self.successors[msg.pipeline_index];
+        let next = match outcome {
+            StageOutcome::Continue => {
+                Ok(successors.first().copied().unwrap_or(self.len()))
+            }
+            StageOutcome::Branch(branch) => successors
+                .get(branch)
+                .copied()
+                .ok_or(StageOutcome::Error("branch out of range")),
+            StageOutcome::JumpTo(stage) if stage == self.len() => Ok(stage),
+            StageOutcome::JumpTo(stage) if stage > self.len() => {
+                Err(StageOutcome::Error("jump out of the pipeline"))
+            }
+            // Jumping back could send the message around forever
+            StageOutcome::JumpTo(stage)
+                if !self.downstream[msg.pipeline_index][stage] =>
+            {
+                Err(StageOutcome::Error("jump to a stage not downstream"))
+            }
+            StageOutcome::JumpTo(stage) => Ok(stage),
+            StageOutcome::Complete => Ok(self.len()),
+            StageOutcome::Drop(_) | StageOutcome::Error(_) => Err(outcome),
+            StageOutcome::FanOut if successors.len() > 1 => {
+                msg.fan_out = Some(FanOut::Successors);
+                return;
+            }
+            StageOutcome::Broadcast if !successors.is_empty() => {
+                msg.fan_out = Some(FanOut::Workers);
+                return;
+            }
+            // Nothing to copy the message to
+            StageOutcome::FanOut | StageOutcome::Broadcast => {
+                Ok(successors.first().copied().unwrap_or(self.len()))
+            }
+        };
+        match next {
+            Ok(next) => msg.pipeline_index = next,
+            Err(discarded) => msg.discarded = Some(discarded),
+        }
+    }
+
+    /// The number of stages in the pipeline.
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    /// Always false for a built pipeline.
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// The largest number of messages each worker runs concurrently in async
+    /// stages.
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    /// How messages of different priority classes are chosen between.
+    pub fn priority_mode(&self) -> PriorityMode {
+        self.priority_mode
+    }
+
+    /// Whether idle workers steal messages from the queues of busy workers.
+    pub fn work_stealing(&self) -> bool {
+        self.work_stealing
+    }
+
+    /// How many messages can be in the pipeline at once.
+    pub fn admission(&self) -> Admission {
+        self.admission
+    }
+
+    /// The largest number of messages moved per send over the data mesh, and
+    /// processed by a worker before it sends them on.
+    pub fn burst_size(&self) -> usize {
+        self.burst_size
+    }
+
+    /// The pool that the messages are given back to when they leave the
+    /// pipeline, if any.
+    pub fn message_pool(&self) -> Option<&MsgPool<MsgData>> {
+        self.message_pool.as_ref()
+    }
+
+    /// The longest time the messages in the pipeline get to finish once no
+    /// new messages are injected.
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    /// What happens to the run when a stage panics.
+    pub fn panic_policy(&self) -> PanicPolicy {
+        self.panic_policy
+    }
+
+    /// The priority class of `msg`, which is its own priority if it has one
+    /// and otherwise the priority of the stage it is at. Messages that are
+    /// done count as being at the first stage.
+    pub(crate) fn priority_class(&self, msg: &Msg<MsgData>) -> usize {
+        let stage = self
+            .stages
+            .get(msg.pipeline_index)
+            .unwrap_or(&self.stages[0]);
+        let priority = msg.priority.unwrap_or(stage.priority) as usize;
+        priority.min(PRIORITY_CLASSES - 1)
+    }
+
+    /// Creates the instances of all stages for a worker
+    pub(crate) fn instantiate(
+        &self,
+        worker_id: usize,
+    ) -> Vec<StageInstance<MsgData>> {
+        self.stages
+            .iter()
+            .map(|stage| stage.instantiate(worker_id))
+            .collect()
+    }
+}
+
+/// Builds a [`Pipeline`] of any length. The stages are run in the order they
+/// are added, unless they name their successors.
+///
+/// # Examples
+///
+/// ```
+/// use rpppp::{
+///     context::StageContext,
+///     pipeline::{Pipeline, StageDescriptor, StageOutcome},
+///     types::{ChannelElement, QueueType},
+/// };
+///
+/// fn parse(
+///     msg: &mut ChannelElement<u32>,
+///     ctx: &mut StageContext,
+/// ) -> StageOutcome {
+///     ctx.count("parsed", 1);
+///     if msg.data == 0 {
+///         return StageOutcome::Drop("empty");
+///     }
+///     StageOutcome::Continue
+/// }
+///
+/// let offset = 10;
+/// let pipeline = Pipeline::builder()
+///     .parallel(parse)
+///     .atomic(move |msg: &mut ChannelElement<u32>, _: &mut StageContext| {
+///         msg.data += offset
+///     })
+///     // Each worker has its own instance of the stage
+///     .stage(StageDescriptor::new(QueueType::Parallel, |_worker_id| {
+///         let mut count = 0;
+///         move |_: &mut ChannelElement<u32>, _: &mut StageContext| count += 1
+///     }))
+///     .build()
+///     .unwrap();
+/// assert_eq!(pipeline.len(), 3);
+/// ```
+///
+/// Async stages can await timers or I/O without blocking the worker:
+///
+/// ```
+/// use rpppp::{
+///     context::StageContext,
+///     pipeline::{Pipeline, StageDescriptor, StageOutcome},
+///     types::{ChannelElement, QueueType},
+/// };
+/// use std::time::Duration;
+///
+/// let pipeline = Pipeline::builder()
+///     .stage(StageDescriptor::from_async_stage(
+///         QueueType::Ordered,
+///         |msg: ChannelElement<u32>, _: &mut StageContext| async move {
+///             glommio::timer::sleep(Duration::from_micros(10)).await;
+///             (msg, StageOutcome::Continue)
+///         },
+///     ))
+///     .max_concurrency(16)
+///     .build()
+///     .unwrap();
+/// assert_eq!(pipeline.max_concurrency(), 16);
+/// ```
+///
+/// Branches are created by naming the successors of the stages:
+///
+/// ```
+/// use rpppp::{
+///     context::StageContext,
+///     pipeline::{Pipeline, StageDescriptor, StageOutcome},
+///     types::{ChannelElement, QueueType},
+/// };
+///
+/// fn classify(
+///     msg: &mut ChannelElement<u8>,
+///     _: &mut StageContext,
+/// ) -> StageOutcome {
+///     match msg.data {
+///         4 => StageOutcome::Branch(0),
+///         6 => StageOutcome::Branch(1),
+///         _ => StageOutcome::Drop("unknown protocol"),
+///     }
+/// }
+/// fn route(_: &mut ChannelElement<u8>, _: &mut StageContext) {}
+///
+/// let pipeline = Pipeline::builder()
+///     .stage(StageDescriptor::parallel(classify).successors(&["ipv4", "ipv6"]))
+///     .stage(
+///         StageDescriptor::parallel(route)
+///             .named("ipv4")
+///             .successors(&["egress"]),
+///     )
+///     .stage(StageDescriptor::parallel(route).named("ipv6"))
+///     .stage(StageDescriptor::atomic(route).named("egress"))
+///     .build()
+///     .unwrap();
+/// assert_eq!(pipeline.successors(0), &[1, 2]);
+/// assert_eq!(pipeline.successors(1), &[3]);
+/// ```
+pub struct PipelineBuilder<MsgData: 'static> {
+    stages: Vec<StageDescriptor<MsgData>>,
+    max_concurrency: usize,
+    priority_mode: PriorityMode,
+    work_stealing: bool,
+    admission: Admission,
+    burst_size: usize,
+    message_pool: Option<MsgPool<MsgData>>,
+    drain_timeout: Duration,
+    panic_policy: PanicPolicy,
+}
+
+impl<MsgData> Default for PipelineBuilder<MsgData> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<MsgData> PipelineBuilder<MsgData> {
+    pub fn new() -> Self {
+        PipelineBuilder {
+            stages: Vec::new(),
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            priority_mode: PriorityMode::default(),
+            work_stealing: false,
+            admission: Admission::default(),
+            burst_size: DEFAULT_BURST_SIZE,
+            message_pool: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            panic_policy: PanicPolicy::default(),
+        }
+    }
+
+    /// Adds a stage to the end of the pipeline.
+    pub fn stage(mut self, stage: StageDescriptor<MsgData>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn parallel<S>(self, stage: S) -> Self
+    where
+        S: Stage<MsgData> + Clone + Send + Sync,
+    {
+        self.stage(StageDescriptor::parallel(stage))
+    }
+
+    pub fn atomic<S>(self, stage: S) -> Self
+    where
+        S: Stage<MsgData> + Clone + Send + Sync,
+    {
+        self.stage(StageDescriptor::atomic(stage))
+    }
+
+    pub fn ordered<S>(self, stage: S) -> Self
+    where
+        S: Stage<MsgData> + Clone + Send + Sync,
+    {
+        self.stage(StageDescriptor::ordered(stage))
+    }
+
+    /// Sets the largest number of messages each worker runs concurrently in
+    /// async stages. The other messages of async stages wait in order, while
+    /// the worker goes on with the sync stages. Defaults to
+    /// [`DEFAULT_MAX_CONCURRENCY`].
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency;
+        self
+    }
+
+    /// Sets how the workers and the controller choose between messages of
+    /// different priority classes. Defaults to [`PriorityMode::Strict`].
+    pub fn priority_mode(mut self, priority_mode: PriorityMode) -> Self {
+        self.priority_mode = priority_mode;
+        self
+    }
+
+    /// Lets idle workers steal the queued messages of parallel and ordered
+    /// stages from busy workers. Messages of atomic stages are never stolen,
+    /// so that a flow is still processed by one worker at a time. Defaults to
+    /// off.
+    pub fn work_stealing(mut self, work_stealing: bool) -> Self {
+        self.work_stealing = work_stealing;
+        self
+    }
+
+    /// Sets how many messages can be in the pipeline at once, and what
+    /// happens to new messages when it is full. Defaults to
+    /// [`Admission::Unlimited`].
+    pub fn admission(mut self, admission: Admission) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    /// Sets the largest number of messages moved per send over the data mesh,
+    /// like the burst size of `rte_event_dequeue_burst`. The workers process
+    /// up to this many queued messages before they send them on, one burst
+    /// per shard. Defaults to [`DEFAULT_BURST_SIZE`].
+    pub fn burst_size(mut self, burst_size: usize) -> Self {
+        self.burst_size = burst_size;
+        self
+    }
+
+    /// Gives the messages taken from `message_pool` back to it when they
+    /// leave the pipeline. Messages that were not taken from the pool are
+    /// dropped as usual.
+    pub fn message_pool(mut self, message_pool: MsgPool<MsgData>) -> Self {
+        self.message_pool = Some(message_pool);
+        self
+    }
+
+    /// Sets the longest time the messages in the pipeline get to go through
+    /// the rest of it once no new messages are injected. The messages that
+    /// are left after that are abandoned, without running their remaining
+    /// stages. Defaults to [`DEFAULT_DRAIN_TIMEOUT`].
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Sets what happens to the run when a stage panics. Defaults to
+    /// [`PanicPolicy::Abort`].
+    pub fn on_panic(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
+
+    /// Validates and creates the pipeline.
+    pub fn build(self) -> Result<Arc<Pipeline<MsgData>>, PipelineError> {
+        if self.stages.is_empty() {
+            return Err(PipelineError::Empty);
+        }
+        if self.max_concurrency == 0 {
+            return Err(PipelineError::ZeroConcurrency);
+        }
+        if let Some(stage) = self
+            .stages
+            .iter()
+            .position(|stage| stage.priority as usize >= PRIORITY_CLASSES)
+        {
+            return Err(PipelineError::InvalidPriority(stage));
+        }
+        if let PriorityMode::Weighted(weights) = self.priority_mode {
+            if weights.contains(&0) {
+                return Err(PipelineError::ZeroWeight);
+            }
+        }
+        if self.admission.limit() == Some(0) {
+            return Err(PipelineError::ZeroAdmissionLimit);
+        }
+        if self.burst_size == 0 {
+            return Err(PipelineError::ZeroBurstSize);
+        }
+
+        let successors = self.resolve_successors()?;
+        if let Some(stage) = find_cycle(&successors) {
+            return Err(PipelineError::Cycle(stage));
+        }
+        let downstream = find_downstream(&successors);
+
+        Ok(Arc::new(Pipeline {
+            stages: self.stages,
+            successors,
+            downstream,
+            max_concurrency: self.max_concurrency,
+            priority_mode: self.priority_mode,
+            work_stealing: self.work_stealing,
+            admission: self.admission,
+            burst_size: self.burst_size,
+            message_pool: self.message_pool,
+            drain_timeout: self.drain_timeout,
+            panic_policy: self.panic_policy,
+        }))
+    }
+
+    /// Gets the indices of the successors of every stage
+    fn resolve_successors(&self) -> Result<Vec<Vec<usize>>, PipelineError> {
+        let mut names = HashMap::new();
+        for (index, stage) in self.stages.iter().enumerate() {
+            if let Some(name) = stage.name {
+                if names.insert(name, index).is_some() {
+                    return Err(PipelineError::DuplicateName(name));
+                }
+            }
+        }
+
+        self.stages
+            .iter()
+            .enumerate()
+            .map(|(index, stage)| match &stage.successors {
+                Some(successors) => successors
+                    .iter()
+                    .map(|name| {
+                        names
+                            .get(name)
+                            .copied()
+                            .ok_or(PipelineError::UnknownStage(name))
+                    })
+                    .collect(),
+                // Continue with the next stage that was added, if any
+                None if index + 1 < self.stages.len() => Ok(vec![index + 1]),
+                None => Ok(Vec::new()),
+            })
+            .collect()
+    }
+}
+
+/// Finds a stage that is part of a cycle in the graph given by `successors`
+fn find_cycle(successors: &[Vec<usize>]) -> Option<usize> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Visit {
+        New,
+        InProgress,
+        Done,
+    }
+
+    // Depth first search, where reaching a stage that is in progress means
+    // that there is a cycle
+    let mut visits = vec![Visit::New; successors.len()];
+    for start in 0..successors.len() {
+        if visits[start] != Visit::New {
+            continue;
+        }
+        visits[start] = Visit::InProgress;
+        let mut stack = vec![(start, 0)];
+        while let Some((stage, edge)) = stack.pop() {
+            let Some(&next) = successors[stage].get(edge) else {
+                visits[stage] = Visit::Done;
+                continue;
+            };
+            stack.push((stage, edge + 1));
+            match visits[next] {
+                Visit::New => {
+                    visits[next] = Visit::InProgress;
+                    stack.push((next, 0));
+                }
+                Visit::InProgress => return Some(next),
+                Visit::Done => {}
+            }
+        }
+    }
+    None
+}
+
+/// Finds the stages that can be reached from each stage in the acyclic graph
+/// given by `successors`, not counting the stage itself.
+fn find_downstream(successors: &[Vec<usize>]) -> Vec<Vec<bool>> {
+    (0..successors.len())
+        .map(|start| {
+            let mut reached = vec![false; successors.len()];
+            let mut stack = successors[start].clone();
+            while let Some(stage) = stack.pop() {
+                if !reached[stage] {
+                    reached[stage] = true;
+                    stack.extend(&successors[stage]);
+                }
+            }
+            reached
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(_msg: &mut ChannelElement<()>, _ctx: &mut StageContext) {}
+
+    #[test]
+    fn test_instantiate() {
+        let pipeline = Pipeline::builder()
+            .stage(StageDescriptor::new(QueueType::Parallel, |worker_id| {
+                move |msg: &mut ChannelElement<usize>, _: &mut StageContext| {
+                    msg.data += worker_id
+                }
+            }))
+            .build()
+            .unwrap();
+
+        let mut msg = Box::new(crate::types::Msg::new(0, 0));
+        for worker_id in 0..3 {
+            let mut ctx = StageContext::new(worker_id);
+            let outcome = match &mut pipeline.instantiate(worker_id)[0] {
+                StageInstance::Sync(stage) => stage.process(&mut msg, &mut ctx),
+                StageInstance::Async(_) => unreachable!(),
+            };
+            assert_eq!(outcome, StageOutcome::Continue);
+        }
+        assert_eq!(msg.data, 3);
+    }
+
+    #[test]
+    fn test_async() {
+        let pipeline = Pipeline::builder()
+            .stage(StageDescriptor::from_async_stage(
+                QueueType::Parallel,
+                |mut msg: ChannelElement<u32>, ctx: &mut StageContext| {
+                    ctx.count("started", 1);
+                    async move {
+                        msg.data += 1;
+                        (msg, StageOutcome::Drop("done"))
+                    }
+                },
+            ))
+            .build()
+            .unwrap();
+
+        let mut ctx = StageContext::new(0);
+        let StageInstance::Async(stage) = &pipeline.instantiate(0)[0] else {
+            panic!("expected an async stage");
+        };
+        let msg = Box::new(crate::types::Msg::new(1, 0));
+        let (msg, outcome) =
+            futures::executor::block_on(stage.process(msg, &mut ctx));
+        assert_eq!(msg.data, 2);
+        assert_eq!(outcome, StageOutcome::Drop("done"));
+        assert_eq!(ctx.take_stats().counter(0, "started"), 1);
+    }
+
+    #[test]
+    fn test_build() {
+        let pipeline = Pipeline::builder()
+            .parallel(stage)
+            .ordered(stage)
+            .atomic(stage)
+            .build()
+            .unwrap();
+
+        assert_eq!(pipeline.len(), 3);
+        assert_eq!(pipeline.stage(1).unwrap().queue_type, QueueType::Ordered);
+        assert!(pipeline.stage(3).is_none());
+    }
+
+    #[test]
+    fn test_graph() {
+        let pipeline = Pipeline::builder()
+            .stage(StageDescriptor::parallel(stage).successors(&["b", "c"]))
+            .stage(StageDescriptor::parallel(stage).named("b"))
+            .stage(StageDescriptor::parallel(stage).named("c").successors(&[]))
+            .build()
+            .unwrap();
+        assert_eq!(pipeline.stage_index("c"), Some(2));
+        assert_eq!(pipeline.successors(1), &[2]);
+        assert!(pipeline.successors(2).is_empty());
+
+        let mut msg = Msg::new((), 0);
+        pipeline.advance(&mut msg, StageOutcome::Branch(1));
+        assert_eq!(msg.pipeline_index, 2);
+        pipeline.advance(&mut msg, StageOutcome::Continue);
+        assert_eq!(msg.pipeline_index, 3);
+
+        let mut msg = Msg::new((), 0);
+        pipeline.advance(&mut msg, StageOutcome::Branch(2));
+        assert_eq!(msg.pipeline_index, 0);
+        assert!(matches!(msg.discarded, Some(StageOutcome::Error(_))));
+
+        let mut msg = Msg::new((), 0);
+        pipeline.advance(&mut msg, StageOutcome::FanOut);
+        assert_eq!(msg.pipeline_index, 0);
+        assert_eq!(msg.fan_out, Some(FanOut::Successors));
+
+        // Only one successor, so there is nothing to fan out to
+        let mut msg = Msg::new((), 0);
+        msg.pipeline_index = 1;
+        pipeline.advance(&mut msg, StageOutcome::FanOut);
+        assert_eq!(msg.pipeline_index, 2);
+        assert_eq!(msg.fan_out, None);
+    }
+
+    #[test]
+    fn test_jump() {
+        let pipeline = Pipeline::builder()
+            .stage(StageDescriptor::parallel(stage).successors(&["b", "c"]))
+            .stage(StageDescriptor::parallel(stage).named("b").successors(&[]))
+            .stage(StageDescriptor::parallel(stage).named("c"))
+            .parallel(stage)
+            .build()
+            .unwrap();
+
+        let mut msg = Msg::new((), 0);
+        pipeline.advance(&mut msg, StageOutcome::JumpTo(3));
+        assert_eq!(msg.pipeline_index, 3);
+        pipeline.advance(&mut msg, StageOutcome::JumpTo(4));
+        assert_eq!(msg.pipeline_index, 4);
+
+        // Backwards, to itself, to another branch and past the end
+        for (from, to) in [(3, 2), (2, 2), (1, 3), (0, 5)] {
+            let mut msg = Msg::new((), 0);
+            msg.pipeline_index = from;
+            pipeline.advance(&mut msg, StageOutcome::JumpTo(to));
+            assert_eq!(msg.pipeline_index, from);
+            assert!(matches!(msg.discarded, Some(StageOutcome::Error(_))));
+        }
+    }
+
+    #[test]
+    fn test_invalid_graph() {
+        let cycle = Pipeline::builder()
+            .parallel(stage)
+            .stage(StageDescriptor::parallel(stage).named("b"))
+            .stage(StageDescriptor::parallel(stage).successors(&["b"]))
+            .build();
+        assert_eq!(cycle.err(), Some(PipelineError::Cycle(1)));
+
+        let unknown = Pipeline::builder()
+            .stage(StageDescriptor::parallel(stage).successors(&["b"]))
+            .build();
+        assert_eq!(unknown.err(), Some(PipelineError::UnknownStage("b")));
+
+        let duplicate = Pipeline::builder()
+            .stage(StageDescriptor::parallel(stage).named("a"))
+            .stage(StageDescriptor::parallel(stage).named("a"))
+            .build();
+        assert_eq!(duplicate.err(), Some(PipelineError::DuplicateName("a")));
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(
+            Pipeline::<()>::builder().build().err(),
+            Some(PipelineError::Empty)
+        );
+        assert_eq!(
+            Pipeline::builder()
+                .parallel(stage)
+                .max_concurrency(0)
+                .build()
+                .err(),
+            Some(PipelineError::ZeroConcurrency)
+        );
+        assert_eq!(
+            Pipeline::builder()
+                .parallel(stage)
+                .admission(Admission::Delay(0))
+                .build()
+                .err(),
+            Some(PipelineError::ZeroAdmissionLimit)
+        );
+        assert_eq!(
+            Pipeline::builder()
+                .parallel(stage)
+                .burst_size(0)
+                .build()
+                .err(),
+            Some(PipelineError::ZeroBurstSize)
+        );
+    }
+
+    #[test]
+    fn test_priority() {
+        let pipeline = Pipeline::builder()
+            .parallel(stage)
+            .stage(StageDescriptor::parallel(stage).priority(2))
+            .build()
+            .unwrap();
+
+        let mut msg = Msg::new((), 0);
+        assert_eq!(pipeline.priority_class(&msg), 0);
+        msg.pipeline_index = 1;
+        assert_eq!(pipeline.priority_class(&msg), 2);
+        msg.priority = Some(1);
+        assert_eq!(pipeline.priority_class(&msg), 1);
+
+        let invalid = Pipeline::builder()
+            .parallel(stage)
+            .stage(StageDescriptor::parallel(stage).priority(4))
+            .build();
+        assert_eq!(invalid.err(), Some(PipelineError::InvalidPriority(1)));
+
+        let zero_weight = Pipeline::builder()
+            .parallel(stage)
+            .priority_mode(PriorityMode::Weighted([4, 2, 1, 0]))
+            .build();
+        assert_eq!(zero_weight.err(), Some(PipelineError::ZeroWeight));
+    }
+}
@@ -0,0 +1,189 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use crate::types::{ChannelElement, Msg};
+
+/// The number of messages a [`LocalPool`] takes from or gives back to the
+/// shared pool at once, like the per-core cache of `rte_mempool`.
+pub const POOL_CACHE_SIZE: usize = 256;
+
+/// The statistics of a [`MsgPool`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PoolStats {
+    /// The number of messages in the pool.
+    pub size: usize,
+    /// Number of times a message was asked for when all were in use, since
+    /// the pool was created.
+    pub exhausted: u64,
+}
+
+struct Shared<MsgData: 'static> {
+    free: Mutex<Vec<ChannelElement<MsgData>>>,
+    size: usize,
+    exhausted: AtomicU64,
+    // Set when a core found no free message, so that the messages given back
+    // go straight to the shared pool instead of the caches of the cores
+    wanted: AtomicBool,
+}
+
+/// A fixed number of pre-allocated messages, like the mbuf pool of DPDK. The
+/// generator takes messages with [`LocalPool::get`] instead of allocating
+/// them, and the controller gives them back when they leave the pipeline.
+/// Every core uses its own [`LocalPool`], which moves the messages to and from
+/// the shared pool in batches. Once the shared pool runs out, the messages
+/// are given back to it one by one, so that a pool smaller than the caches
+/// does not end up cached by the cores that give messages back.
+pub struct MsgPool<MsgData: 'static> {
+    shared: Arc<Shared<MsgData>>,
+}
+
+// Derived Clone would require MsgData to be Clone
+impl<MsgData> Clone for MsgPool<MsgData> {
+    fn clone(&self) -> Self {
+        MsgPool {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<MsgData: Default> MsgPool<MsgData> {
+    /// Allocates a pool of `size` messages.
+    pub fn new(size: usize) -> Self {
+        let free = (0..size)
+            .map(|_| Box::new(Msg::new(MsgData::default(), 0)))
+            .collect();
+        MsgPool {
+            shared: Arc::new(Shared {
+                free: Mutex::new(free),
+                size,
+                exhausted: AtomicU64::new(0),
+                wanted: AtomicBool::new(false),
+            }),
+        }
+    }
+}
+
+impl<MsgData> MsgPool<MsgData> {
+    /// Creates the cache of the pool for the current core.
+    pub fn local(&self) -> LocalPool<MsgData> {
+        LocalPool {
+            pool: self.clone(),
+            cache: Vec::with_capacity(2 * POOL_CACHE_SIZE),
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            size: self.shared.size,
+            exhausted: self.shared.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The messages of a [`MsgPool`] cached by one core.
+pub struct LocalPool<MsgData: 'static> {
+    pool: MsgPool<MsgData>,
+    cache: Vec<ChannelElement<MsgData>>,
+}
+
+impl<MsgData> LocalPool<MsgData> {
+    /// Takes a message from the pool and sets it up like [`Msg::new`].
+    /// Returns [`None`] if all messages are in use.
+    pub fn get(
+        &mut self,
+        data: MsgData,
+        flow_id: u64,
+    ) -> Option<ChannelElement<MsgData>> {
+        let shared = &self.pool.shared;
+        if self.cache.is_empty() {
+            let mut free = shared.free.lock().unwrap();
+            let start = free.len().saturating_sub(POOL_CACHE_SIZE);
+            self.cache.extend(free.drain(start..));
+            shared
+                .wanted
+                .store(self.cache.is_empty(), Ordering::Relaxed);
+        }
+        let Some(mut msg) = self.cache.pop() else {
+            shared.exhausted.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        *msg = Msg::new(data, flow_id);
+        msg.pooled = true;
+        Some(msg)
+    }
+
+    /// Gives back `msg` if it was taken from a pool, or else drops it.
+    pub(crate) fn put(&mut self, msg: ChannelElement<MsgData>) {
+        if !msg.pooled {
+            return;
+        }
+        self.cache.push(msg);
+        if self.pool.shared.wanted.load(Ordering::Relaxed) {
+            // Another core is waiting for a message
+            let mut free = self.pool.shared.free.lock().unwrap();
+            free.append(&mut self.cache);
+        } else if self.cache.len() >= 2 * POOL_CACHE_SIZE {
+            let start = self.cache.len() - POOL_CACHE_SIZE;
+            let mut free = self.pool.shared.free.lock().unwrap();
+            free.extend(self.cache.drain(start..));
+        }
+    }
+}
+
+impl<MsgData> Drop for LocalPool<MsgData> {
+    fn drop(&mut self) {
+        let mut free = self.pool.shared.free.lock().unwrap();
+        free.append(&mut self.cache);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool() {
+        let pool = MsgPool::<u32>::new(POOL_CACHE_SIZE + 1);
+        let mut generator = pool.local();
+        let mut controller = pool.local();
+
+        let msgs: Vec<_> = (0..POOL_CACHE_SIZE as u32 + 1)
+            .map(|i| generator.get(i, 3).unwrap())
+            .collect();
+        assert_eq!((msgs[0].data, msgs[0].flow_id), (0, 3));
+        assert!(generator.get(0, 0).is_none());
+        assert_eq!(pool.stats().exhausted, 1);
+
+        // Copies are not part of the pool
+        let mut copy = msgs[0].clone();
+        copy.pooled = false;
+        controller.put(copy);
+        for msg in msgs {
+            controller.put(msg);
+        }
+        drop(controller);
+        assert!(generator.get(7, 0).is_some());
+        assert_eq!(pool.shared.free.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_small_pool() {
+        let pool = MsgPool::<u32>::new(10);
+        let mut generator = pool.local();
+        let mut controller = pool.local();
+
+        // The messages must not stay in the cache of the controller, which
+        // is far from full
+        for _ in 0..3 {
+            let msgs: Vec<_> =
+                std::iter::from_fn(|| generator.get(0, 0)).collect();
+            assert_eq!(msgs.len(), 10);
+            for msg in msgs {
+                controller.put(msg);
+            }
+        }
+        assert_eq!(pool.stats().exhausted, 3);
+    }
+}
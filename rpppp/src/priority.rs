@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+
+/// The number of priority classes. Class 0 has the highest priority, like the
+/// queue priorities of DPDK eventdev.
+pub const PRIORITY_CLASSES: usize = 4;
+
+/// The priority class of stages that do not set one.
+pub const DEFAULT_PRIORITY: u8 = 0;
+
+/// How the workers and the controller choose between messages of different
+/// priority classes that are waiting to be processed or sent.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PriorityMode {
+    /// Messages of a class are only taken when all classes with higher
+    /// priority are empty. Lower classes can starve.
+    #[default]
+    Strict,
+    /// The classes take turns, in priority order, and each class gets to take
+    /// up to its weight in messages per turn. Every weight must be at least
+    /// one.
+    Weighted([u32; PRIORITY_CLASSES]),
+}
+
+/// A FIFO queue per priority class, where [`PriorityQueues::pop`] takes from
+/// the classes as decided by the [`PriorityMode`].
+pub(crate) struct PriorityQueues<T> {
+    queues: [VecDeque<T>; PRIORITY_CLASSES],
+    mode: PriorityMode,
+    len: usize,
+    // The class whose turn it is and how many messages it can still take, for
+    // the weighted mode
+    turn: usize,
+    credits: u32,
+}
+
+impl<T> PriorityQueues<T> {
+    pub(crate) fn new(mode: PriorityMode) -> Self {
+        PriorityQueues {
+            queues: Default::default(),
+            mode,
+            len: 0,
+            turn: 0,
+            credits: match mode {
+                PriorityMode::Strict => 0,
+                PriorityMode::Weighted(weights) => weights[0],
+            },
+        }
+    }
+
+    /// Adds `item` last in the queue of `class`.
+    pub(crate) fn push(&mut self, class: usize, item: T) {
+        self.queues[class].push_back(item);
+        self.len += 1;
+    }
+
+    /// Takes the next item to handle, or [`None`] if all queues are empty.
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        match self.mode {
+            PriorityMode::Strict => {
+                self.queues.iter_mut().find_map(|queue| queue.pop_front())
+            }
+            PriorityMode::Weighted(weights) => loop {
+                if self.credits > 0 {
+                    if let Some(item) = self.queues[self.turn].pop_front() {
+                        self.credits -= 1;
+                        return Some(item);
+                    }
+                }
+                // An empty class gives up the rest of its turn
+                self.turn = (self.turn + 1) % PRIORITY_CLASSES;
+                self.credits = weights[self.turn];
+            },
+        }
+    }
+
+    /// Takes up to `max` items that `stealable` accepts, lowest priority and
+    /// newest first, so that the items left keep their order.
+    pub(crate) fn steal(
+        &mut self,
+        max: usize,
+        stealable: impl Fn(&T) -> bool,
+    ) -> Vec<T> {
+        let mut stolen = Vec::new();
+        for queue in self.queues.iter_mut().rev() {
+            let mut index = queue.len();
+            while index > 0 && stolen.len() < max {
+                index -= 1;
+                if stealable(&queue[index]) {
+                    stolen.push(queue.remove(index).unwrap());
+                }
+            }
+        }
+        self.len -= stolen.len();
+        stolen
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(queues: &mut PriorityQueues<(usize, usize)>) {
+        for i in 0..4 {
+            queues.push(3, (3, i));
+            queues.push(1, (1, i));
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut queues = PriorityQueues::new(PriorityMode::Strict);
+        fill(&mut queues);
+        assert_eq!(queues.len(), 8);
+
+        let popped: Vec<_> = std::iter::from_fn(|| queues.pop()).collect();
+        assert_eq!(popped[..4], [(1, 0), (1, 1), (1, 2), (1, 3)]);
+        assert_eq!(popped[4..], [(3, 0), (3, 1), (3, 2), (3, 3)]);
+        assert!(queues.is_empty());
+    }
+
+    #[test]
+    fn test_weighted() {
+        let mut queues = PriorityQueues::new(PriorityMode::Weighted([1; 4]));
+        fill(&mut queues);
+        let classes: Vec<_> = std::iter::from_fn(|| queues.pop())
+            .map(|(c, _)| c)
+            .collect();
+        assert_eq!(classes, [1, 3, 1, 3, 1, 3, 1, 3]);
+
+        let mut queues =
+            PriorityQueues::new(PriorityMode::Weighted([1, 3, 1, 1]));
+        fill(&mut queues);
+        let popped: Vec<_> = std::iter::from_fn(|| queues.pop()).collect();
+        assert_eq!(
+            popped,
+            [
+                (1, 0),
+                (1, 1),
+                (1, 2),
+                (3, 0),
+                (1, 3),
+                (3, 1),
+                (3, 2),
+                (3, 3)
+            ]
+        );
+        assert!(queues.pop().is_none());
+    }
+
+    #[test]
+    fn test_steal() {
+        let mut queues = PriorityQueues::new(PriorityMode::Strict);
+        fill(&mut queues);
+        let stolen = queues.steal(5, |&(_, i)| i % 2 == 1);
+        assert_eq!(stolen, [(3, 3), (3, 1), (1, 3), (1, 1)]);
+        assert_eq!(queues.len(), 4);
+        let popped: Vec<_> = std::iter::from_fn(|| queues.pop()).collect();
+        assert_eq!(popped, [(1, 0), (1, 2), (3, 0), (3, 2)]);
+    }
+}
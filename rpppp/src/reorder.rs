@@ -0,0 +1,295 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+use crate::types::Msg;
+
+/// Stamps messages with a per-flow sequence number when they enter an ordered
+/// stage. A flow only takes memory while it has messages in the stage, see
+/// [`Sequencer::forget`].
+#[derive(Default)]
+pub struct Sequencer {
+    next_seq: HashMap<(usize, u64), u64>,
+}
+
+impl Sequencer {
+    /// Gives `msg` the next sequence number of its flow in the stage it is
+    /// about to enter.
+    pub fn stamp<MsgData>(&mut self, msg: &mut Msg<MsgData>) {
+        let next_seq = self
+            .next_seq
+            .entry((msg.pipeline_index, msg.flow_id))
+            .or_insert(0);
+        msg.ordered_stage = Some(msg.pipeline_index);
+        msg.seq = *next_seq;
+        *next_seq += 1;
+    }
+
+    /// Forgets the flow `flow_id` of the ordered stage `stage` if all of its
+    /// messages have left the stage, which is when `released` messages have.
+    /// Returns whether it was forgotten, and then its sequence numbers start
+    /// over, so the reorder buffer must forget it too.
+    pub fn forget(
+        &mut self,
+        stage: usize,
+        flow_id: u64,
+        released: u64,
+    ) -> bool {
+        let key = (stage, flow_id);
+        let forget = self.next_seq.get(&key) == Some(&released);
+        if forget {
+            self.next_seq.remove(&key);
+        }
+        forget
+    }
+
+    /// The number of flows that have messages in ordered stages.
+    pub fn flows(&self) -> usize {
+        self.next_seq.len()
+    }
+}
+
+/// Statistics about how much reordering was needed at egress.
+#[derive(Clone, Debug, Default)]
+pub struct ReorderStats {
+    /// Number of messages that have passed through the reorder buffer.
+    pub released: u64,
+    /// Number of messages that arrived out of order and had to wait.
+    pub buffered: u64,
+    /// The largest number of messages waiting in the buffer at once.
+    pub max_depth: usize,
+    arrivals: u64,
+    depth_sum: u64,
+}
+
+impl ReorderStats {
+    /// The average number of messages waiting in the buffer, sampled every
+    /// time a message arrives.
+    pub fn average_depth(&self) -> f64 {
+        if self.arrivals == 0 {
+            return 0.0;
+        }
+        self.depth_sum as f64 / self.arrivals as f64
+    }
+}
+
+/// Holds back messages that arrive before their predecessors in the same
+/// flow, so that they are released strictly in sequence. `K` identifies the
+/// flow.
+pub struct ReorderBuffer<K, T> {
+    next_seq: HashMap<K, u64>,
+    pending: HashMap<K, BTreeMap<u64, Option<T>>>,
+    depth: usize,
+    stats: ReorderStats,
+}
+
+impl<K: Hash + Eq + Copy, T> Default for ReorderBuffer<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Copy, T> ReorderBuffer<K, T> {
+    pub fn new() -> Self {
+        Self {
+            next_seq: HashMap::new(),
+            pending: HashMap::new(),
+            depth: 0,
+            stats: ReorderStats::default(),
+        }
+    }
+
+    /// Adds `item` with sequence number `seq` in flow `flow_id`. `release` is
+    /// called for every item that is next in sequence, in order.
+    pub fn insert(
+        &mut self,
+        flow_id: K,
+        seq: u64,
+        item: T,
+        release: impl FnMut(T),
+    ) {
+        self.arrive(flow_id, seq, Some(item), release);
+    }
+
+    /// Lets the items after sequence number `seq` in flow `flow_id` be
+    /// released without the item with `seq`, which will never arrive.
+    /// `release` is called like for [`ReorderBuffer::insert`].
+    pub fn skip(&mut self, flow_id: K, seq: u64, release: impl FnMut(T)) {
+        self.arrive(flow_id, seq, None, release);
+    }
+
+    /// Adds `item` with sequence number `seq`, where [`None`] is an item that
+    /// is skipped.
+    fn arrive(
+        &mut self,
+        flow_id: K,
+        seq: u64,
+        item: Option<T>,
+        mut release: impl FnMut(T),
+    ) {
+        let next_seq = self.next_seq.entry(flow_id).or_insert(0);
+        self.stats.arrivals += 1;
+
+        if seq != *next_seq {
+            // Arrived early, wait for the missing messages
+            self.pending.entry(flow_id).or_default().insert(seq, item);
+            self.depth += 1;
+            self.stats.buffered += 1;
+            self.stats.max_depth = self.stats.max_depth.max(self.depth);
+            self.stats.depth_sum += self.depth as u64;
+            return;
+        }
+
+        let mut released = item;
+        *next_seq += 1;
+        loop {
+            if let Some(item) = released {
+                release(item);
+                self.stats.released += 1;
+            }
+            let Some(pending) = self.pending.get_mut(&flow_id) else {
+                break;
+            };
+            match pending.remove(next_seq) {
+                Some(item) => {
+                    released = item;
+                    *next_seq += 1;
+                    self.depth -= 1;
+                }
+                None => break,
+            }
+            // No flow keeps an empty map of waiting items
+            if pending.is_empty() {
+                self.pending.remove(&flow_id);
+            }
+        }
+        self.stats.depth_sum += self.depth as u64;
+    }
+
+    /// The number of items of flow `flow_id` that have been released or
+    /// skipped, or [`None`] if some of its items are waiting.
+    pub fn released(&self, flow_id: K) -> Option<u64> {
+        match self.pending.contains_key(&flow_id) {
+            true => None,
+            false => Some(self.next_seq.get(&flow_id).copied().unwrap_or(0)),
+        }
+    }
+
+    /// Forgets flow `flow_id`, whose next item will have sequence number
+    /// zero. It must have no items waiting.
+    pub fn forget(&mut self, flow_id: K) {
+        debug_assert!(!self.pending.contains_key(&flow_id));
+        self.next_seq.remove(&flow_id);
+    }
+
+    /// The number of flows that the buffer keeps track of.
+    pub fn flows(&self) -> usize {
+        self.next_seq.len()
+    }
+
+    /// The number of messages currently waiting in the buffer.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn stats(&self) -> &ReorderStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_order() {
+        let mut rob = ReorderBuffer::new();
+        let mut released = Vec::new();
+        for seq in 0..4u64 {
+            rob.insert(0, seq, seq, |s| released.push(s));
+        }
+        assert_eq!(released, vec![0, 1, 2, 3]);
+        assert_eq!(rob.stats().buffered, 0);
+        assert_eq!(rob.depth(), 0);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut rob = ReorderBuffer::new();
+        let mut released = Vec::new();
+        rob.insert(0, 2, (0, 2), |s| released.push(s));
+        rob.insert(1, 0, (1, 0), |s| released.push(s));
+        rob.insert(0, 1, (0, 1), |s| released.push(s));
+        assert_eq!(released, vec![(1, 0)]);
+        assert_eq!(rob.depth(), 2);
+
+        rob.insert(0, 0, (0, 0), |s| released.push(s));
+        assert_eq!(released, vec![(1, 0), (0, 0), (0, 1), (0, 2)]);
+        assert_eq!(rob.depth(), 0);
+        assert_eq!(rob.stats().max_depth, 2);
+        assert_eq!(rob.stats().buffered, 2);
+        assert_eq!(rob.stats().released, 4);
+    }
+
+    #[test]
+    fn test_skip() {
+        let mut rob = ReorderBuffer::new();
+        let mut released = Vec::new();
+        rob.insert(0, 1, 1, |s| released.push(s));
+        rob.skip(0, 2, |s| released.push(s));
+        rob.insert(0, 3, 3, |s| released.push(s));
+        assert!(released.is_empty());
+
+        rob.skip(0, 0, |s| released.push(s));
+        assert_eq!(released, vec![1, 3]);
+        assert_eq!(rob.depth(), 0);
+        assert_eq!(rob.stats().released, 2);
+    }
+
+    #[test]
+    fn test_forget() {
+        let mut sequencer = Sequencer::default();
+        let mut rob = ReorderBuffer::new();
+        let mut messages: Vec<_> = (0..3)
+            .map(|flow_id| {
+                let mut msg = Msg::new((), flow_id % 2);
+                msg.pipeline_index = 1;
+                sequencer.stamp(&mut msg);
+                msg
+            })
+            .collect();
+        assert_eq!(sequencer.flows(), 2);
+
+        // Flow 0 still has a message in the stage after its first one left
+        let first = messages.remove(0);
+        rob.insert((1, 0), first.seq, first, |_| {});
+        assert_eq!(rob.released((1, 0)), Some(1));
+        assert!(!sequencer.forget(1, 0, 1));
+
+        // Flow 1 is done, and so is flow 0 once its last message is skipped
+        let last = messages.remove(0);
+        rob.insert((1, 1), last.seq, last, |_| {});
+        assert!(sequencer.forget(1, 1, rob.released((1, 1)).unwrap()));
+        rob.forget((1, 1));
+        rob.skip((1, 0), messages[0].seq, |_| {});
+        assert!(sequencer.forget(1, 0, rob.released((1, 0)).unwrap()));
+        rob.forget((1, 0));
+        assert_eq!((sequencer.flows(), rob.flows()), (0, 0));
+
+        // A forgotten flow starts over
+        let mut msg = Msg::new((), 0);
+        msg.pipeline_index = 1;
+        sequencer.stamp(&mut msg);
+        assert_eq!(msg.seq, 0);
+    }
+
+    #[test]
+    fn test_no_empty_pending() {
+        let mut rob = ReorderBuffer::new();
+        rob.insert(0, 1, 1, |_| {});
+        assert_eq!(rob.released(0), None);
+        rob.insert(0, 0, 0, |_| {});
+        assert_eq!(rob.released(0), Some(2));
+    }
+}
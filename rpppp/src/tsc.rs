@@ -1,6 +1,25 @@
+//! This module is used to execute a loop for a specific amount of TSC cycles.
+//! First, run [`calibration`] to get the number needed to run for a
+//! certain number of TSC cycles. This result is then used with [`burn`]
+//! to run for the original number of TSC cycles.
+//!
+//! # Examples
+//!
+//! ```
+//! use rpppp::tsc;
+//!
+//! let cycles = tsc::calibration(200);
+//! // print how many TSC cycles that it on average took to run
+//! println!("200 TSC cycles average was {}", tsc::cycles_average(cycles));
+//! ```
+
 #[cfg(debug_assertions)]
 use rand::distributions::{Distribution, Uniform};
 
+#[cfg(target_arch = "x86")]
+use std::arch::x86 as arch;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64 as arch;
 #[cfg_attr(debug_assertions, allow(unused_imports))]
 use std::cmp::max;
 use std::{
@@ -8,22 +27,53 @@ use std::{
     panic,
     time::{Duration, Instant},
 };
-use tsc_time::has_invariant_tsc;
-
-/// This module is used to execute a loop for a specific amount of TSC cycles.
-/// First, run [`calibration`] to get the number needed to run for a
-/// certain number of TSC cycles. This result is then used with [`burn`]
-/// to run for the original number of TSC cycles.
-///
-/// # Examples
-///
-/// ```
-/// use rpppp::tsc;
-///
-/// let cycles = tsc::calibration(200);
-/// // print how many TSC cycles that it on average took to run
-/// println!("200 TSC cycles average was {}", tsc::cycles_average(cycles));
-/// ```
+
+/// Checks if the CPU has an invariant TSC, which is advertised in bit 8 of
+/// EDX of the CPUID leaf 0x8000_0007. Without it, the measured cycles might
+/// be unreliable.
+fn has_invariant_tsc() -> bool {
+    // CPUID is safe on newer toolchains
+    #[allow(unused_unsafe)]
+    let cpuid = |leaf| unsafe { arch::__cpuid(leaf) };
+    cpuid(0x8000_0000).eax >= 0x8000_0007
+        && cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Reads the TSC at the start of a measurement. CPUID keeps earlier
+/// instructions from running after the TSC is read.
+fn tsc_start() -> u64 {
+    #[allow(unused_unsafe)]
+    unsafe {
+        arch::__cpuid(0);
+        arch::_rdtsc()
+    }
+}
+
+/// Reads the TSC at the end of a measurement. RDTSCP waits for the earlier
+/// instructions, and CPUID keeps later instructions from running before it.
+fn tsc_stop() -> u64 {
+    let mut core = 0;
+    #[allow(unused_unsafe)]
+    unsafe {
+        let tsc = arch::__rdtscp(&mut core);
+        arch::__cpuid(0);
+        tsc
+    }
+}
+
+/// Gets the number of TSC cycles it takes to run `f`, without the cycles it
+/// takes to measure. Panics if the measurement takes fewer cycles than the
+/// measuring overhead.
+fn span(f: impl Fn()) -> u64 {
+    let start = tsc_start();
+    f();
+    let duration = tsc_stop() - start;
+    let start = tsc_start();
+    black_box(0);
+    let overhead = tsc_stop() - start;
+    assert!(overhead <= duration);
+    duration - overhead
+}
 
 /// This function is used to burn cycles and shouldn't be able to be
 /// optimized away by the rust compiler.
@@ -47,7 +97,7 @@ pub fn cycles_average(iterations: u64) -> u64 {
     while i < MEASUREMENTS {
         let result = panic::catch_unwind(|| {
             // span sometimes panics, so another measurement must be run
-            tsc_time::Duration::span(|| burn(iterations)).0.cycles()
+            span(|| burn(iterations))
         });
 
         if let Ok(latency) = result {
@@ -62,11 +112,11 @@ pub fn cycles_average(iterations: u64) -> u64 {
 /// Gets the frequency of the processors tsc-counter.
 pub fn get_tsc_hz() -> u64 {
     let start = Instant::now();
-    let start_tsc = tsc_time::Start::now();
+    let start_tsc = tsc_start();
     std::thread::sleep(Duration::from_secs(1));
     let elapsed = start.elapsed();
-    let stop_tsc = tsc_time::Stop::now();
-    ((stop_tsc - start_tsc).cycles() * 1_000_000) / elapsed.as_micros() as u64
+    let stop_tsc = tsc_stop();
+    ((stop_tsc - start_tsc) * 1_000_000) / elapsed.as_micros() as u64
 }
 
 #[cfg(debug_assertions)]
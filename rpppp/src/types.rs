@@ -1,7 +1,18 @@
-use std::time::Instant;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
 
 use glommio::channels::channel_mesh::FullMesh;
 
+use crate::{
+    context::{Stats, StatsHistogram},
+    flows::FlowSlot,
+    pipeline::{FanOut, StageOutcome},
+    pool::PoolStats,
+    reorder::ReorderStats,
+};
+
 // These are the IDs that we assume the controller will get. We assert in the
 // code that this is correct.
 pub const DATA_MESH_CONTROLLER_ID: usize = 0;
@@ -9,33 +20,103 @@ pub const CONTROL_MESH_CONTROLLER_ID: usize = 0;
 
 // The type of data that is sent over the channels
 pub type ChannelElement<MsgData> = Box<Msg<MsgData>>;
-pub type DataMesh<MsgData> = FullMesh<ChannelElement<MsgData>>;
-
-/// Each message has a pipeline of functions that will be run on the message
-/// data. This is the type used for these pipelines. Each pipeline will start
-/// with the functions to run (at least one) and then end with (at least one)
-/// [`None`]. Each pipeline will be [`PIPELINE_SIZE`] elements long.
-pub type PipelineElement<MsgData> =
-    Option<fn(&mut ChannelElement<MsgData>, usize)>;
-/// The maximum length of each pipeline.
-pub const PIPELINE_SIZE: usize = 5;
+// The messages sent together over the data mesh
+pub type Burst<MsgData> = Vec<ChannelElement<MsgData>>;
+pub type DataMesh<MsgData> = FullMesh<Burst<MsgData>>;
+
 pub const MESH_CHANNEL_SIZE: usize = 8192;
 
 /// This is the message that is sent between the generator, the controller and
-/// the workers. It contains the message data, a chain of functions to call on
-/// the data, and an index to which function to call.
+/// the workers. It contains the message data and an index to which stage of
+/// the pipeline to run on the data.
+///
+/// A [`VectorMsg`] is an event vector, which carries many packets of a flow
+/// through the pipeline as one message.
 #[derive(Clone)]
 pub struct Msg<MsgData: 'static> {
     pub data: MsgData,
-    pub pipeline: &'static [PipelineElement<MsgData>; PIPELINE_SIZE],
+    /// The stage to run next, or the stage that discarded the message or that
+    /// it fans out from.
     pub pipeline_index: usize,
+    /// Decides which worker processes the message in an atomic stage.
+    pub flow_id: u64,
+    /// The index of the ordered stage the message is in, if any.
+    pub ordered_stage: Option<usize>,
+    /// The position of the message in its flow when it entered the ordered
+    /// stage.
+    pub seq: u64,
     pub timestamp: Instant,
+    /// Overrides the priority class of the stages the message goes through.
+    /// Messages of a flow are only kept in order within a priority class.
+    pub priority: Option<u8>,
+    /// When the message was created, for the latency of the whole pipeline.
+    pub(crate) created: Instant,
+    /// Set when a stage drops the message or fails. The message then returns
+    /// to the controller, which counts it.
+    pub(crate) discarded: Option<StageOutcome>,
+    /// Set when the message is to be copied. The message then returns to the
+    /// controller, which copies it.
+    pub(crate) fan_out: Option<FanOut>,
+    /// The worker that a copy is sent to, until that worker is done with it.
+    pub(crate) target_shard: Option<usize>,
+    /// The flow group the message is counted in while it is on its way to an
+    /// atomic stage.
+    pub(crate) flow_slot: FlowSlot,
+    /// The flow group the previous stage was done with, which is released
+    /// once the message has arrived.
+    pub(crate) released_slot: Option<usize>,
+    /// Set while the message is on its way to a worker that stole it from
+    /// the queue of another worker.
+    pub(crate) stolen: bool,
+    /// Set if the message belongs to a [`crate::pool::MsgPool`].
+    pub(crate) pooled: bool,
+}
+
+impl<MsgData> Msg<MsgData> {
+    /// Creates a message that starts at the first stage of the pipeline.
+    pub fn new(data: MsgData, flow_id: u64) -> Self {
+        let now = Instant::now();
+        Msg {
+            data,
+            pipeline_index: 0,
+            flow_id,
+            ordered_stage: None,
+            seq: 0,
+            timestamp: now,
+            priority: None,
+            created: now,
+            discarded: None,
+            fan_out: None,
+            target_shard: None,
+            flow_slot: FlowSlot::None,
+            released_slot: None,
+            stolen: false,
+            pooled: false,
+        }
+    }
 }
 
-/// Communicate certain stages of the process between shards.
-#[derive(PartialEq, Debug)]
+/// A message carrying a vector of packets, created by the
+/// [`crate::vector::Aggregator`] and processed by the stages of
+/// [`crate::pipeline::StageDescriptor::vector`].
+pub type VectorMsg<T> = Msg<Vec<T>>;
+
+/// Communicate certain stages of the process between shards. A run ends with
+/// [`ControlMessage::StopIngress`], [`ControlMessage::Drain`] and
+/// [`ControlMessage::Shutdown`] from the controller, in that order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ControlMessage {
     WorkerInitializationComplete,
+    /// No new messages are injected, so the workers stop moving work between
+    /// each other. The messages in the pipeline still go through all of it.
+    StopIngress,
+    /// The messages in the pipeline have left it, or have run out of time.
+    /// The workers give back the messages they still have to the controller
+    /// without processing them, and answer with
+    /// [`ControlMessage::DrainComplete`].
+    Drain,
+    /// The worker has no messages left.
+    DrainComplete,
     Shutdown,
 }
 pub type ControlMesh = FullMesh<ControlMessage>;
@@ -46,3 +127,263 @@ pub enum SchedulingType {
     Sw,
     Dsw,
 }
+
+/// How messages are scheduled to a stage of the pipeline. Mirrors the schedule
+/// types of DPDK eventdev.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QueueType {
+    /// Messages are distributed round robin over the workers, so messages of
+    /// the same flow can be processed concurrently.
+    Parallel,
+    /// All messages of a flow are processed by the same worker at each stage,
+    /// so at most one worker processes a flow at a stage at a time.
+    Atomic,
+    /// Messages are processed in parallel like [`QueueType::Parallel`], but
+    /// the controller restores the order of each flow before the messages
+    /// continue to the next stage.
+    Ordered,
+}
+
+/// The result of a run.
+#[derive(Clone, Debug)]
+pub struct RunReport {
+    /// The time it took to generate and process the messages.
+    pub run_duration: Duration,
+    /// The time it took for the messages in the pipeline to leave it once no
+    /// new messages were injected.
+    pub drain_duration: Duration,
+    /// The number of cores used by the run, including the generator and the
+    /// controller.
+    pub cores: usize,
+    /// Number of messages that were sent into the pipeline.
+    pub injected_packets: u64,
+    /// Number of messages that went through the whole pipeline.
+    pub processed_packets: u64,
+    /// Number of messages and copies that left the pipeline, however they
+    /// left it.
+    pub returned_packets: u64,
+    /// Number of extra messages created when messages fanned out.
+    pub copies: u64,
+    /// Number of messages that were still in the pipeline when the drain
+    /// timeout ran out, so they never went through all of it.
+    pub abandoned_packets: u64,
+    /// Number of messages that async stages panicked on, which were lost
+    /// with their futures.
+    pub lost_packets: u64,
+    /// Number of messages dropped by a stage, per stage index and reason.
+    pub drops: BTreeMap<(usize, &'static str), u64>,
+    /// Number of messages a stage failed to process, per stage index and
+    /// reason.
+    pub errors: BTreeMap<(usize, &'static str), u64>,
+    /// How much the messages had to be reordered after the ordered stages.
+    pub reorder: ReorderStats,
+    /// The counters and histograms recorded by the stages on all workers.
+    pub stats: Stats,
+    /// The time in microseconds from when the messages were created until
+    /// they went through the whole pipeline, per priority class. The class of
+    /// a message is its own priority, or else that of the first stage.
+    pub priority_latency: Vec<StatsHistogram>,
+    /// What each worker has done, by worker id.
+    pub workers: Vec<WorkerStats>,
+    /// Number of new messages discarded because the pipeline was full.
+    pub rejected_injections: u64,
+    /// Number of new messages that had to wait because the pipeline was
+    /// full.
+    pub delayed_injections: u64,
+    /// The statistics of the message pool of the pipeline, if it has one.
+    pub pool: Option<PoolStats>,
+}
+
+impl RunReport {
+    /// Number of messages dropped by all stages.
+    pub fn dropped_packets(&self) -> u64 {
+        self.drops.values().sum()
+    }
+
+    /// Number of messages that failed in any stage.
+    pub fn failed_packets(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    /// The share of [`RunReport::total_duration`] that each worker spent
+    /// running stages, by worker id. The workers also run stages while the
+    /// pipeline drains.
+    pub fn utilisation(&self) -> Vec<f64> {
+        let total = self.total_duration().as_secs_f64();
+        self.workers
+            .iter()
+            .map(|worker| worker.busy.as_secs_f64() / total)
+            .collect()
+    }
+
+    /// The time from the start of the run until the pipeline was drained.
+    pub fn total_duration(&self) -> Duration {
+        self.run_duration + self.drain_duration
+    }
+
+    /// The time until the pipeline was drained that each worker spent not
+    /// running stages, by worker id.
+    pub fn idle(&self) -> Vec<Duration> {
+        self.workers
+            .iter()
+            .map(|worker| self.total_duration().saturating_sub(worker.busy))
+            .collect()
+    }
+
+    /// Number of messages each stage has processed on all workers, by stage
+    /// index.
+    pub fn stage_packets(&self) -> Vec<u64> {
+        self.sum_stages(|worker| &worker.stages)
+    }
+
+    /// Number of times each stage has panicked on all workers, by stage
+    /// index.
+    pub fn stage_panics(&self) -> Vec<u64> {
+        self.sum_stages(|worker| &worker.panics)
+    }
+
+    /// Adds up the per-stage counters of all workers.
+    fn sum_stages(
+        &self,
+        counters: impl Fn(&WorkerStats) -> &[u64],
+    ) -> Vec<u64> {
+        let mut stages = Vec::new();
+        for worker in &self.workers {
+            let counters = counters(worker);
+            stages.resize(stages.len().max(counters.len()), 0);
+            for (stage, count) in counters.iter().enumerate() {
+                stages[stage] += count;
+            }
+        }
+        stages
+    }
+
+    /// The number of million messages that went through the whole pipeline
+    /// per second, until it was drained. The messages that finish while the
+    /// pipeline drains are counted too.
+    pub fn throughput_mpps(&self) -> f64 {
+        self.processed_packets as f64 / self.total_duration().as_micros() as f64
+    }
+
+    /// The number of TSC cycles of work done on the messages that went
+    /// through the whole pipeline, when each took `cycles_per_packet`.
+    pub fn done_work(&self, cycles_per_packet: u64) -> u64 {
+        self.processed_packets * cycles_per_packet
+    }
+
+    /// The number of TSC cycles that all cores could have worked until the
+    /// pipeline was drained, with a TSC frequency of `tsc_hz`.
+    pub fn ideal_work(&self, tsc_hz: u64) -> u64 {
+        (self.cores as f64
+            * self.total_duration().as_secs_f64()
+            * tsc_hz as f64) as u64
+    }
+
+    /// The share of [`RunReport::ideal_work`] that was
+    /// [`RunReport::done_work`].
+    pub fn core_efficiency(&self, cycles_per_packet: u64, tsc_hz: u64) -> f64 {
+        self.done_work(cycles_per_packet) as f64
+            / self.ideal_work(tsc_hz) as f64
+    }
+
+    /// The latency of the messages that went through the whole pipeline,
+    /// for all priority classes.
+    pub fn latency(&self) -> StatsHistogram {
+        let mut latency = StatsHistogram::new();
+        for class in &self.priority_latency {
+            latency.add_data_from(class);
+        }
+        latency
+    }
+
+    /// The histograms `name` of all stages merged, or [`None`] if no stage
+    /// recorded any value in it.
+    pub fn histogram(&self, name: &str) -> Option<StatsHistogram> {
+        let histogram = self.stats.total_histogram(name);
+        (histogram.count() > 0).then_some(histogram)
+    }
+}
+
+/// What a worker has done during a run.
+#[derive(Clone, Default, Debug)]
+pub struct WorkerStats {
+    /// Number of stages the worker has run on messages.
+    pub processed: u64,
+    /// Number of messages the worker has run each stage on, by stage index.
+    pub stages: Vec<u64>,
+    /// Number of times each stage has panicked on the worker, by stage index.
+    pub panics: Vec<u64>,
+    /// The time the worker has spent running stages. For async stages, only
+    /// the time until the future is returned.
+    pub busy: Duration,
+    /// Number of flow groups this worker has started migrating to other
+    /// workers.
+    pub migrations: u64,
+    /// Number of messages this worker has stolen from the queues of other
+    /// workers.
+    pub stolen: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> RunReport {
+        let worker =
+            |busy_ms, stages: Vec<u64>, panics: Vec<u64>| WorkerStats {
+                stages,
+                panics,
+                busy: Duration::from_millis(busy_ms),
+                ..Default::default()
+            };
+        RunReport {
+            run_duration: Duration::from_secs(3),
+            drain_duration: Duration::from_secs(1),
+            cores: 4,
+            injected_packets: 2_000_000,
+            processed_packets: 1_000_000,
+            returned_packets: 2_000_000,
+            copies: 0,
+            abandoned_packets: 0,
+            lost_packets: 0,
+            drops: BTreeMap::from([
+                ((0, "full"), 500_000),
+                ((1, "ttl"), 400_000),
+            ]),
+            errors: BTreeMap::from([((1, "parse"), 100_000)]),
+            reorder: ReorderStats::default(),
+            stats: Stats::default(),
+            priority_latency: Vec::new(),
+            workers: vec![
+                worker(1000, vec![1, 2], vec![0, 1]),
+                worker(3000, vec![3], vec![2]),
+            ],
+            rejected_injections: 0,
+            delayed_injections: 0,
+            pool: None,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let report = report();
+        assert_eq!(report.dropped_packets(), 900_000);
+        assert_eq!(report.failed_packets(), 100_000);
+        assert_eq!(report.total_duration(), Duration::from_secs(4));
+        // The messages that finished while the pipeline drained are counted
+        // over the whole duration
+        assert_eq!(report.utilisation(), vec![0.25, 0.75]);
+        assert_eq!(
+            report.idle(),
+            vec![Duration::from_secs(3), Duration::from_secs(1)]
+        );
+        assert_eq!(report.stage_packets(), vec![4, 2]);
+        assert_eq!(report.stage_panics(), vec![2, 1]);
+        assert_eq!(report.throughput_mpps(), 0.25);
+        assert_eq!(report.done_work(10), 10_000_000);
+        assert_eq!(report.ideal_work(1_000_000), 16_000_000);
+        assert_eq!(report.core_efficiency(10, 1_000_000), 0.625);
+        assert_eq!(report.latency().count(), 0);
+        assert!(report.histogram("latency").is_none());
+    }
+}
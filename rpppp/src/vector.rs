@@ -0,0 +1,377 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use glommio::channels::shared_channel::ConnectedSender;
+
+use crate::{
+    context::StageContext,
+    controller::Injector,
+    error::Error,
+    pipeline::{Stage, StageDescriptor, StageOutcome},
+    types::{ChannelElement, QueueType, VectorMsg},
+};
+
+/// A stage that runs on all the packets of an event vector at once, for
+/// stages like checksums or lookups that are faster in batches. Created with
+/// [`StageDescriptor::vector`].
+#[derive(Clone)]
+pub struct VectorStage<F>(pub F);
+
+impl<T, F, R> Stage<Vec<T>> for VectorStage<F>
+where
+    F: FnMut(&mut [T], &mut StageContext) -> R + 'static,
+    R: Into<StageOutcome>,
+{
+    fn process(
+        &mut self,
+        msg: &mut ChannelElement<Vec<T>>,
+        ctx: &mut StageContext,
+    ) -> StageOutcome {
+        (self.0)(&mut msg.data, ctx).into()
+    }
+}
+
+impl<T: 'static> StageDescriptor<Vec<T>> {
+    /// Creates a stage where each worker gets a clone of `stage`, which gets
+    /// the packets of each event vector as a slice. The outcome applies to the
+    /// whole vector.
+    pub fn vector<F, R>(queue_type: QueueType, stage: F) -> Self
+    where
+        F: FnMut(&mut [T], &mut StageContext) -> R
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        R: Into<StageOutcome>,
+    {
+        Self::from_stage(queue_type, VectorStage(stage))
+    }
+}
+
+/// Collects packets at ingress into event vectors, one per flow, like the
+/// event vector adapter of DPDK eventdev. A vector is done when it has
+/// `max_size` packets, or when `timeout` has passed since its first packet.
+/// The packets of a flow keep their order, and a vector is created when its
+/// first packet arrives.
+pub struct Aggregator<T: 'static> {
+    max_size: usize,
+    timeout: Duration,
+    vectors: HashMap<u64, VectorMsg<T>>,
+    // The flows of the vectors, oldest first
+    started: VecDeque<(Instant, u64)>,
+}
+
+impl<T> Aggregator<T> {
+    /// Fails with [`Error::ZeroVectorSize`] if `max_size` is zero.
+    pub fn new(max_size: usize, timeout: Duration) -> Result<Self, Error> {
+        if max_size == 0 {
+            return Err(Error::ZeroVectorSize);
+        }
+        Ok(Aggregator {
+            max_size,
+            timeout,
+            vectors: HashMap::new(),
+            started: VecDeque::new(),
+        })
+    }
+
+    /// Adds `packet` to the vector of `flow_id`. Returns the vector if it is
+    /// full.
+    pub fn push(
+        &mut self,
+        flow_id: u64,
+        packet: T,
+    ) -> Option<ChannelElement<Vec<T>>> {
+        let vector = self.vectors.entry(flow_id).or_insert_with(|| {
+            let vector =
+                VectorMsg::new(Vec::with_capacity(self.max_size), flow_id);
+            self.started.push_back((vector.created, flow_id));
+            vector
+        });
+        vector.data.push(packet);
+        if vector.data.len() < self.max_size {
+            return None;
+        }
+        self.vectors.remove(&flow_id).map(Box::new)
+    }
+
+    /// Takes the vectors that have waited for at least the timeout.
+    pub fn expired(&mut self) -> Vec<ChannelElement<Vec<T>>> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while let Some(&(started, flow_id)) = self.started.front() {
+            if now.duration_since(started) < self.timeout {
+                break;
+            }
+            self.started.pop_front();
+            // The vector may already have been sent full, and a new one
+            // started for the flow
+            if self.vectors.get(&flow_id).map(|vector| vector.created)
+                == Some(started)
+            {
+                expired.push(Box::new(self.vectors.remove(&flow_id).unwrap()));
+            }
+        }
+        expired
+    }
+
+    /// Takes all the vectors, full or not, oldest first.
+    pub fn flush(&mut self) -> Vec<ChannelElement<Vec<T>>> {
+        self.started.clear();
+        let mut vectors: Vec<_> = self
+            .vectors
+            .drain()
+            .map(|(_, vector)| Box::new(vector))
+            .collect();
+        vectors.sort_by_key(|vector| vector.created);
+        vectors
+    }
+
+    /// The number of packets waiting for their vector to be done.
+    pub fn pending(&self) -> usize {
+        self.vectors.values().map(|vector| vector.data.len()).sum()
+    }
+}
+
+/// An [`Injector`] that collects the packets into event vectors with an
+/// [`Aggregator`] before they are injected, for [`crate::core::start_dsw`].
+/// The packets of rejected vectors are counted.
+pub struct VectorInjector<T: Send + 'static> {
+    injector: Injector<Vec<T>>,
+    aggregator: Aggregator<T>,
+    rejected: u64,
+}
+
+impl<T: Send + Clone> VectorInjector<T> {
+    pub fn new(injector: Injector<Vec<T>>, aggregator: Aggregator<T>) -> Self {
+        VectorInjector {
+            injector,
+            aggregator,
+            rejected: 0,
+        }
+    }
+
+    /// Adds `packet` to the vector of `flow_id`, and injects the vectors that
+    /// are full or have timed out. Returns false if a vector was rejected, in
+    /// which case the generator should back off like with
+    /// [`Injector::inject`].
+    pub async fn inject(&mut self, flow_id: u64, packet: T) -> bool {
+        let mut injected = true;
+        if let Some(vector) = self.aggregator.push(flow_id, packet) {
+            injected = self.inject_vector(vector).await;
+        }
+        self.inject_expired().await && injected
+    }
+
+    /// Injects the vectors that have timed out. A generator that pauses
+    /// should call this regularly. Returns false if a vector was rejected.
+    pub async fn inject_expired(&mut self) -> bool {
+        let mut injected = true;
+        for vector in self.aggregator.expired() {
+            injected &= self.inject_vector(vector).await;
+        }
+        injected
+    }
+
+    /// Injects the remaining vectors, full or not. Returns false if a vector
+    /// was rejected.
+    pub async fn flush(&mut self) -> bool {
+        let mut injected = true;
+        for vector in self.aggregator.flush() {
+            injected &= self.inject_vector(vector).await;
+        }
+        injected
+    }
+
+    /// The number of packets in vectors that were rejected.
+    pub fn rejected_packets(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Injects the remaining vectors and gives back the injector, which is
+    /// what the generator of [`crate::core::start_dsw`] returns.
+    pub async fn into_injector(mut self) -> Injector<Vec<T>> {
+        self.flush().await;
+        self.injector
+    }
+
+    async fn inject_vector(&mut self, vector: ChannelElement<Vec<T>>) -> bool {
+        let packets = vector.data.len() as u64;
+        let injected = self.injector.inject(vector).await;
+        if !injected {
+            self.rejected += packets;
+        }
+        injected
+    }
+}
+
+/// Sends the packets to the controller of [`crate::core::start_sw`] in event
+/// vectors, which are collected with an [`Aggregator`]. The controller
+/// discards the vectors once the run limit is reached.
+pub struct VectorSender<T: Send + 'static> {
+    sender: ConnectedSender<ChannelElement<Vec<T>>>,
+    aggregator: Aggregator<T>,
+}
+
+impl<T: Send> VectorSender<T> {
+    pub fn new(
+        sender: ConnectedSender<ChannelElement<Vec<T>>>,
+        aggregator: Aggregator<T>,
+    ) -> Self {
+        VectorSender { sender, aggregator }
+    }
+
+    /// Adds `packet` to the vector of `flow_id`, and sends the vectors that
+    /// are full or have timed out. Fails if the controller has stopped
+    /// receiving.
+    pub async fn send(&mut self, flow_id: u64, packet: T) -> Result<(), Error> {
+        if let Some(vector) = self.aggregator.push(flow_id, packet) {
+            self.send_vector(vector).await?;
+        }
+        self.send_expired().await
+    }
+
+    /// Sends the vectors that have timed out. A generator that pauses should
+    /// call this regularly.
+    pub async fn send_expired(&mut self) -> Result<(), Error> {
+        for vector in self.aggregator.expired() {
+            self.send_vector(vector).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the remaining vectors, full or not. The generator should call
+    /// this before it returns.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        for vector in self.aggregator.flush() {
+            self.send_vector(vector).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_vector(
+        &self,
+        vector: ChannelElement<Vec<T>>,
+    ) -> Result<(), Error> {
+        self.sender
+            .send(vector)
+            .await
+            .map_err(|_| Error::ChannelClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{run_dsw, run_sw},
+        dispatch::DispatchPolicy,
+        limit::RunLimit,
+        pipeline::Pipeline,
+    };
+    use glommio::{Placement, PoolPlacement};
+    use std::sync::{Arc, Mutex};
+
+    fn count(packets: &mut [u32], ctx: &mut StageContext) {
+        ctx.count("packets", packets.len() as u64);
+    }
+
+    #[test]
+    fn test_aggregator() {
+        assert!(matches!(
+            Aggregator::<char>::new(0, Duration::ZERO),
+            Err(Error::ZeroVectorSize)
+        ));
+        let mut aggregator =
+            Aggregator::new(3, Duration::from_millis(50)).unwrap();
+        assert!(aggregator.push(1, 'a').is_none());
+        assert!(aggregator.push(2, 'b').is_none());
+        assert!(aggregator.push(1, 'c').is_none());
+        let full = aggregator.push(1, 'd').unwrap();
+        assert_eq!((full.flow_id, full.data), (1, vec!['a', 'c', 'd']));
+        assert!(aggregator.push(1, 'e').is_none());
+        assert!(aggregator.expired().is_empty());
+        assert_eq!(aggregator.pending(), 2);
+
+        std::thread::sleep(Duration::from_millis(50));
+        let expired = aggregator.expired();
+        let expired: Vec<_> = expired
+            .iter()
+            .map(|v| (v.flow_id, v.data.clone()))
+            .collect();
+        assert_eq!(expired, [(2, vec!['b']), (1, vec!['e'])]);
+        assert_eq!(aggregator.pending(), 0);
+    }
+
+    #[test]
+    fn test_vector_injector() {
+        let pipeline = Pipeline::builder()
+            .stage(StageDescriptor::vector(QueueType::Parallel, count))
+            .build()
+            .unwrap();
+        let rejected = Arc::new(Mutex::new(None));
+        let generator_rejected = rejected.clone();
+        let report = run_dsw(
+            PoolPlacement::Unbound(2),
+            Placement::Unbound,
+            pipeline,
+            DispatchPolicy::RoundRobin,
+            move |injector, _| {
+                let rejected = generator_rejected.clone();
+                async move {
+                    let aggregator =
+                        Aggregator::new(4, Duration::from_secs(60)).unwrap();
+                    let mut injector =
+                        VectorInjector::new(injector, aggregator);
+                    let mut injected = 0;
+                    for packet in 0..100 {
+                        injected += injector.inject(0, packet).await as u64;
+                    }
+                    *rejected.lock().unwrap() =
+                        Some((injected, injector.rejected_packets()));
+                    injector.into_injector().await
+                }
+            },
+            // Ten vectors
+            RunLimit::PacketCount(10),
+        )
+        .unwrap();
+
+        // Every packet that was not processed was counted as rejected
+        assert_eq!(report.stats.total_counter("packets"), 40);
+        assert_eq!(*rejected.lock().unwrap(), Some((85, 60)));
+    }
+
+    #[test]
+    fn test_vector_sender() {
+        let pipeline = Pipeline::builder()
+            .stage(StageDescriptor::vector(QueueType::Atomic, count))
+            .build()
+            .unwrap();
+        let report = run_sw(
+            PoolPlacement::Unbound(2),
+            Placement::Unbound,
+            Placement::Unbound,
+            pipeline,
+            DispatchPolicy::RoundRobin,
+            |sender, _| async move {
+                let aggregator =
+                    Aggregator::new(4, Duration::from_secs(60)).unwrap();
+                let mut sender =
+                    VectorSender::new(sender.connect().await, aggregator);
+                for packet in 0..100 {
+                    sender.send(packet as u64 % 3, packet).await.unwrap();
+                }
+                sender.flush().await.unwrap();
+            },
+            RunLimit::Unlimited,
+        )
+        .unwrap();
+
+        // Eight full vectors per flow, and the rest of each flow
+        assert_eq!(report.processed_packets, 24 + 3);
+        assert_eq!(report.stats.total_counter("packets"), 100);
+    }
+}
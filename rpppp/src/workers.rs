@@ -1,72 +1,152 @@
-use std::{cell::RefCell, time::Instant};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use futures::future::Either;
+use futures::future::LocalBoxFuture;
 use futures_lite::{future::ready, FutureExt};
 use glommio::{
     channels::{
         channel_mesh::MeshBuilder,
-        sharding::{Handler, HandlerResult, Sharded},
+        sharding::{Handler, HandlerResult},
     },
     enclose,
     prelude::*,
+    sync::{Semaphore, StaticPermit},
+    timer::{self, sleep},
     CpuSet,
 };
 
-use crate::controller::increment_round_robin;
+use crate::context::{StageContext, Stats};
+use crate::dispatch::{Dispatcher, WorkerLoad};
+use crate::error::Error;
+use crate::failure::{Failures, LostMessage, StagePanic, PANIC_OUTCOME};
+use crate::flows::{FlowSlot, FlowTable};
+use crate::mesh::MeshShard;
+use crate::pipeline::{Pipeline, StageInstance, StageOutcome};
+use crate::priority::PriorityQueues;
 use crate::types::{
-    ChannelElement, ControlMesh, ControlMessage, DataMesh, SchedulingType,
-    CONTROL_MESH_CONTROLLER_ID, DATA_MESH_CONTROLLER_ID, MESH_CHANNEL_SIZE,
+    Burst, ChannelElement, ControlMesh, ControlMessage, DataMesh, QueueType,
+    SchedulingType, WorkerStats, CONTROL_MESH_CONTROLLER_ID,
+    DATA_MESH_CONTROLLER_ID, MESH_CHANNEL_SIZE,
 };
 
-type ShardRequest<MsgData> =
-    Sharded<ChannelElement<MsgData>, RequestHandler<MsgData>>;
+/// How often the DSW workers sample their load and consider migrating flows
+const FLOW_SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+/// The number of outstanding messages a worker must have before it migrates
+/// any flows
+const MIGRATION_MIN_LOAD: usize = 64;
+/// How often an idle worker looks for a busy worker to steal from
+const STEAL_INTERVAL: Duration = Duration::from_micros(100);
+/// The number of outstanding messages a worker must have before it is stolen
+/// from
+const STEAL_MIN_LOAD: usize = 16;
+/// The largest number of messages stolen at once
+const STEAL_BATCH: usize = 32;
 
 /// Handles the messages within the main sharding mesh.
 #[derive(Clone)]
 struct RequestHandler<MsgData: Send + 'static> {
-    _nr_shards: usize,
     scheduling_type: SchedulingType,
-    shard: *mut *mut ShardRequest<MsgData>,
-    rr_counter: RefCell<usize>,
-    stop_time: Instant,
+    shard: MeshShard<Burst<MsgData>>,
+    dispatcher: Rc<Dispatcher>,
+    load: Arc<WorkerLoad>,
+    flows: Arc<FlowTable>,
+    // Messages of the flow groups migrating to this worker, held until the
+    // previous owner is done with the flow group
+    held: Rc<RefCell<HashMap<usize, Vec<ChannelElement<MsgData>>>>>,
+    // Messages processed per owned flow group since the last sample
+    flow_counts: Rc<RefCell<HashMap<usize, u64>>>,
+    // Whether the background tasks of the worker keep running
+    running: Rc<Cell<bool>>,
+    // Whether new messages are still injected, until StopIngress
+    ingress: Rc<Cell<bool>>,
+    // Whether the messages are given back to the controller without being
+    // processed, from Drain
+    draining: Rc<Cell<bool>>,
+    pipeline: Arc<Pipeline<MsgData>>,
+    // The instances of the stages for this worker
+    stages: Rc<RefCell<Vec<StageInstance<MsgData>>>>,
+    context: Rc<RefCell<StageContext>>,
+    // The messages waiting to be processed, and a permit for each of them
+    queue: Rc<RefCell<PriorityQueues<ChannelElement<MsgData>>>>,
+    waiting: Rc<Semaphore>,
+    // The processed messages of the current burst, per shard to send them to
+    outbox: Rc<RefCell<Vec<Burst<MsgData>>>>,
+    // Limits how many messages are in async stages at once
+    concurrency: Rc<Semaphore>,
+    // The messages of async stages waiting for a permit from `concurrency`,
+    // oldest first, and a permit for each of them
+    parked: Rc<RefCell<VecDeque<ChannelElement<MsgData>>>>,
+    nr_parked: Rc<Semaphore>,
+    // The messages in async stages by task, which are abandoned if they are
+    // not done when the drain times out
+    in_async: Rc<RefCell<HashMap<u64, LostMessage>>>,
+    next_task: Rc<Cell<u64>>,
+    worker_stats: Rc<Cell<WorkerStats>>,
+    failures: Arc<Failures>,
 }
 
-impl<MsgData: Send + Clone> Handler<ChannelElement<MsgData>>
+impl<MsgData: Send + Clone> Handler<Burst<MsgData>>
     for RequestHandler<MsgData>
 {
     fn handle(
         &self,
-        msg: ChannelElement<MsgData>,
+        burst: Burst<MsgData>,
         _src_shard: usize,
         _cur_shard: usize,
     ) -> HandlerResult {
-        let shard_pointer = self.shard;
-        let scheduling_type = self.scheduling_type.clone();
-        let stop_time = self.stop_time;
-
-        let next_shard =
-            increment_round_robin(&self.rr_counter, self._nr_shards);
+        let mut returned = Vec::new();
+        for mut msg in burst {
+            if let Some(slot) = msg.released_slot.take() {
+                self.flows.done(slot);
+            }
+            if std::mem::take(&mut msg.stolen) {
+                let mut worker_stats = self.worker_stats.take();
+                worker_stats.stolen += 1;
+                self.worker_stats.set(worker_stats);
+            }
 
-        // must detach or else deadlock is possible
-        glommio::executor()
-            .spawn_local(async move {
-                let shard = unsafe {
-                    shard_pointer.as_ref().unwrap().as_ref().unwrap()
-                };
-                if stop_time > Instant::now() {
-                    RequestHandler::worker_function(
-                        scheduling_type,
-                        msg,
-                        shard,
-                        next_shard,
-                        stop_time,
-                    )
-                    .await;
-                } else {
-                    // Send result back to the controller
-                    shard.send_to(DATA_MESH_CONTROLLER_ID, msg).await.unwrap();
+            if !self.draining.get() {
+                // Never wait here, or else deadlock is possible. The messages
+                // are processed by `process_queue`.
+                match msg.flow_slot {
+                    FlowSlot::Paused(slot) => {
+                        self.held
+                            .borrow_mut()
+                            .entry(slot)
+                            .or_default()
+                            .push(msg);
+                        self.release_held(slot);
+                    }
+                    FlowSlot::Owned(slot) => {
+                        // The held messages were sent first
+                        self.release_held(slot);
+                        self.enqueue(msg);
+                    }
+                    FlowSlot::None => self.enqueue(msg),
                 }
-            })
-            .detach();
+            } else {
+                self.done(&mut msg);
+                returned.push(msg);
+            }
+        }
+
+        if !returned.is_empty() {
+            let handler = self.clone();
+            // Send results back to the controller
+            glommio::executor()
+                .spawn_local(async move {
+                    handler.send(DATA_MESH_CONTROLLER_ID, returned).await;
+                })
+                .detach();
+        }
 
         ready(()).boxed_local()
     }
@@ -75,91 +155,597 @@ impl<MsgData: Send + Clone> Handler<ChannelElement<MsgData>>
 impl<MsgData: Send + Clone> RequestHandler<MsgData> {
     fn new(
         scheduling_type: SchedulingType,
-        num_shards: usize,
-        stop_time: Instant,
+        pipeline: Arc<Pipeline<MsgData>>,
+        dispatcher: Dispatcher,
+        failures: Arc<Failures>,
+        shard: MeshShard<Burst<MsgData>>,
     ) -> Self {
+        let nr_shards = shard.nr_shards();
         RequestHandler {
-            _nr_shards: num_shards,
             scheduling_type,
-            shard: Box::into_raw(Box::new(std::ptr::null_mut())),
-            rr_counter: RefCell::from(0),
-            stop_time,
+            shard,
+            load: dispatcher.load().clone(),
+            flows: dispatcher.flows().clone(),
+            dispatcher: Rc::new(dispatcher),
+            held: Rc::new(RefCell::new(HashMap::new())),
+            flow_counts: Rc::new(RefCell::new(HashMap::new())),
+            running: Rc::new(Cell::new(true)),
+            ingress: Rc::new(Cell::new(true)),
+            draining: Rc::new(Cell::new(false)),
+            stages: Rc::new(RefCell::new(Vec::new())),
+            context: Rc::new(RefCell::new(StageContext::new(0))),
+            queue: Rc::new(RefCell::new(PriorityQueues::new(
+                pipeline.priority_mode(),
+            ))),
+            waiting: Rc::new(Semaphore::new(0)),
+            outbox: Rc::new(RefCell::new(
+                (0..nr_shards).map(|_| Vec::new()).collect(),
+            )),
+            concurrency: Rc::new(Semaphore::new(
+                pipeline.max_concurrency() as u64
+            )),
+            parked: Rc::new(RefCell::new(VecDeque::new())),
+            nr_parked: Rc::new(Semaphore::new(0)),
+            in_async: Rc::new(RefCell::new(HashMap::new())),
+            next_task: Rc::new(Cell::new(0)),
+            worker_stats: Rc::new(Cell::new(WorkerStats {
+                stages: vec![0; pipeline.len()],
+                panics: vec![0; pipeline.len()],
+                ..Default::default()
+            })),
+            failures,
+            pipeline,
         }
     }
 
-    fn set_shard(&self, shard: &mut ShardRequest<MsgData>) {
-        // Handler must be created before the shard, but the shard must be
-        // saved in the handler, so therefore the unsafe pointer
-        unsafe {
-            *self.shard.as_mut().unwrap() = shard;
+    /// Creates the instances of the stages that this worker runs and their
+    /// context. Can only be done once the shard id, and therefore the worker
+    /// id, is known.
+    fn set_stages(&self, worker_id: usize) {
+        *self.stages.borrow_mut() = self.pipeline.instantiate(worker_id);
+        *self.context.borrow_mut() = StageContext::new(worker_id);
+        self.dispatcher.set_shard_id(worker_id + 1);
+    }
+
+    fn shard_id(&self) -> usize {
+        self.context.borrow().worker_id() + 1
+    }
+
+    /// Counts that this worker is done with a message that was sent to it.
+    /// Its flow group is released by the shard it is sent to next.
+    fn done(&self, message: &mut ChannelElement<MsgData>) {
+        self.load.done(self.context.borrow().worker_id());
+        message.target_shard = None;
+        if let FlowSlot::Owned(slot) = std::mem::take(&mut message.flow_slot) {
+            *self.flow_counts.borrow_mut().entry(slot).or_insert(0) += 1;
+            message.released_slot = Some(slot);
         }
     }
 
-    /// Performs a function in the message pipeline before sending it on if
-    /// more functions are left in the pipeline.
-    async fn worker_function(
-        scheduling_type: SchedulingType,
-        mut message: ChannelElement<MsgData>,
-        shard: &ShardRequest<MsgData>,
-        next_shard: usize,
-        _stop_time: Instant,
-    ) {
-        // Assume that there is work in the pipeline
-        let func = message.pipeline[message.pipeline_index].unwrap();
-        func(&mut message, shard.shard_id() - 1);
-        message.pipeline_index += 1;
+    /// Queues `message` to be processed in its priority class
+    fn enqueue(&self, message: ChannelElement<MsgData>) {
+        let class = self.pipeline.priority_class(&message);
+        self.queue.borrow_mut().push(class, message);
+        self.waiting.signal(1);
+    }
 
-        if scheduling_type == SchedulingType::Sw
-            || message.pipeline[message.pipeline_index].is_none()
+    /// Queues the held messages of the flow group `slot`, if it has finished
+    /// migrating to this worker
+    fn release_held(&self, slot: usize) {
+        if !self.held.borrow().contains_key(&slot)
+            || !self.flows.is_settled(slot, self.shard_id())
         {
-            // Send result back to the controller
-            shard
-                .send_to(DATA_MESH_CONTROLLER_ID, message)
-                .await
-                .unwrap();
             return;
         }
+        let held = self.held.borrow_mut().remove(&slot).unwrap();
+        for mut message in held {
+            // They were never counted by the flow table
+            message.flow_slot = FlowSlot::None;
+            self.enqueue(message);
+        }
+    }
 
-        // More work to do and uses DSW, so is sent to next shard
-        shard.send_to(next_shard, message).await.unwrap();
+    /// Samples the load of the workers until the worker shuts down, and
+    /// releases the held messages of the flow groups that have migrated here.
+    async fn balance_flows(&self) {
+        while self.running.get() {
+            sleep(FLOW_SAMPLE_INTERVAL).await;
+            let held: Vec<_> = self.held.borrow().keys().copied().collect();
+            for slot in held {
+                self.release_held(slot);
+            }
+            if self.ingress.get() {
+                self.migrate_flow();
+            }
+        }
+    }
+
+    /// Migrates a busy flow group from this worker to the least loaded worker,
+    /// if this worker has at least twice its load. The flow group must not
+    /// have more than half of the recent messages of this worker, or else the
+    /// overload would just move with it.
+    fn migrate_flow(&self) {
+        let flow_counts = std::mem::take(&mut *self.flow_counts.borrow_mut());
+        let shard = self.shard_id();
+        let load = self.load.outstanding(shard);
+        let (idle, idle_load) = (1..self.load.nr_shards())
+            .map(|shard| (shard, self.load.outstanding(shard)))
+            .min_by_key(|(_, load)| *load)
+            .unwrap();
+        if load < MIGRATION_MIN_LOAD || idle_load * 2 > load {
+            return;
+        }
+
+        let total: u64 = flow_counts.values().sum();
+        let mut flow_counts: Vec<_> = flow_counts.into_iter().collect();
+        flow_counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let slot = flow_counts.into_iter().find(|(slot, count)| {
+            count * 2 <= total
+                && self.flows.is_settled(*slot, shard)
+                && !self.held.borrow().contains_key(slot)
+        });
+        if let Some((slot, _)) = slot {
+            if self.flows.migrate(slot, shard, idle).is_some() {
+                let mut worker_stats = self.worker_stats.take();
+                worker_stats.migrations += 1;
+                self.worker_stats.set(worker_stats);
+            }
+        }
+    }
+
+    /// Asks the busiest worker for some of its queued messages whenever this
+    /// worker has nothing to do, until the worker shuts down.
+    async fn steal_work(&self) {
+        let shard = self.shard_id();
+        while self.running.get() {
+            sleep(STEAL_INTERVAL).await;
+            if !self.queue.borrow().is_empty() || !self.ingress.get() {
+                continue;
+            }
+            let (victim, victim_load) = (1..self.load.nr_shards())
+                .filter(|&victim| victim != shard)
+                .map(|victim| (victim, self.load.outstanding(victim)))
+                .max_by_key(|(_, load)| *load)
+                .unwrap_or((shard, 0));
+            if victim_load >= STEAL_MIN_LOAD {
+                self.load.request_steal(victim, shard);
+            }
+        }
+    }
+
+    /// Sends up to half of the stealable queued messages to the worker that
+    /// has asked to steal from this worker, if any.
+    fn give_work(&self) {
+        let Some(thief) = self.load.take_steal_request(self.shard_id()) else {
+            return;
+        };
+        let max = (self.queue.borrow().len() / 2).min(STEAL_BATCH);
+        // Takes the permits of the messages that may be stolen, which fails
+        // only once the worker shuts down
+        if max == 0 || !matches!(self.waiting.try_acquire(max as u64), Ok(true))
+        {
+            return;
+        }
+        let mut stolen = self
+            .queue
+            .borrow_mut()
+            .steal(max, |message| self.is_stealable(message));
+        self.waiting.signal((max - stolen.len()) as u64);
+        if stolen.is_empty() {
+            return;
+        }
+
+        let worker_id = self.context.borrow().worker_id();
+        for message in &mut stolen {
+            self.load.done(worker_id);
+            self.dispatcher.to_shard(thief);
+            message.stolen = true;
+        }
+        let handler = self.clone();
+        glommio::executor()
+            .spawn_local(async move {
+                handler.send(thief, stolen).await;
+            })
+            .detach();
+    }
+
+    /// Checks if `message` can be processed by any worker. Messages of atomic
+    /// stages must stay with the owner of their flow, and copies sent to a
+    /// specific worker must stay with that worker.
+    fn is_stealable(&self, message: &ChannelElement<MsgData>) -> bool {
+        message.flow_slot == FlowSlot::None
+            && message.target_shard.is_none()
+            && self
+                .pipeline
+                .stage(message.pipeline_index)
+                .unwrap()
+                .queue_type
+                != QueueType::Atomic
+    }
+
+    /// Processes the queued messages in bursts, highest priority first, until
+    /// the worker shuts down. The messages of a burst are sent on together
+    /// once all of them have been processed. Once the worker drains, the
+    /// messages are given back to the controller instead.
+    async fn process_queue(&self) {
+        let burst_size = self.pipeline.burst_size();
+        while self.waiting.acquire(1).await.is_ok() {
+            let extra = (self.waiting.available() as usize).min(burst_size - 1);
+            // Nothing else waits for the permits, so they are all available
+            // until the worker shuts down
+            let extra = match self.waiting.try_acquire(extra as u64) {
+                Ok(true) => extra,
+                _ => 0,
+            };
+            for _ in 0..=extra {
+                let mut message = self.queue.borrow_mut().pop().unwrap();
+                if self.draining.get() {
+                    self.done(&mut message);
+                    self.outbox.borrow_mut()[DATA_MESH_CONTROLLER_ID]
+                        .push(message);
+                } else {
+                    self.worker_function(message);
+                }
+            }
+            self.flush().await;
+            if self.pipeline.work_stealing() {
+                self.give_work();
+            }
+            // Let the channels fill the queue
+            glommio::yield_if_needed().await;
+        }
+    }
+
+    /// Starts the parked messages of async stages in order as permits from
+    /// `concurrency` free up, until the worker shuts down.
+    async fn start_parked(&self) {
+        while self.nr_parked.acquire(1).await.is_ok() {
+            let Ok(permit) = self.concurrency.acquire_static_permit(1).await
+            else {
+                break;
+            };
+            // The parked messages are given back once the worker drains
+            let message = self.parked.borrow_mut().pop_front();
+            if let Some(message) = message {
+                self.run_stage(message, Some(permit));
+            }
+        }
+    }
+
+    /// Gives back the messages of this worker to the controller without
+    /// processing them, and waits for the messages in async stages until the
+    /// drain timeout. The messages still in async stages after that are
+    /// abandoned. The messages that arrive later are given back as they
+    /// arrive.
+    async fn drain(&self) {
+        self.draining.set(true);
+        let mut held: Vec<_> = self
+            .held
+            .borrow_mut()
+            .drain()
+            .flat_map(|(_, messages)| messages)
+            .collect();
+        held.extend(self.parked.borrow_mut().drain(..));
+        let _ = self.nr_parked.try_acquire(self.nr_parked.available());
+        for message in &mut held {
+            self.done(message);
+        }
+        if !held.is_empty() {
+            self.send(DATA_MESH_CONTROLLER_ID, held).await;
+        }
+
+        let max_concurrency = self.pipeline.max_concurrency() as u64;
+        let finished = async {
+            self.give_back_queue().await;
+            self.concurrency.acquire(max_concurrency).await.unwrap();
+            Ok(())
+        };
+        let _ = timer::timeout(self.pipeline.drain_timeout(), finished).await;
+        // Lets the messages waiting for a permit be given back, even if the
+        // messages in async stages never finish
+        self.concurrency.signal(max_concurrency);
+        // The messages of the async stages that did not finish in time, or
+        // whose futures were dropped without finishing
+        let abandoned: Vec<_> = self.in_async.borrow_mut().drain().collect();
+        for (_, mut lost) in abandoned {
+            lost.abandoned = true;
+            self.lose(lost).await;
+        }
+        self.give_back_queue().await;
+    }
+
+    /// Waits until `process_queue` has given back the queued messages.
+    async fn give_back_queue(&self) {
+        while !self.queue.borrow().is_empty()
+            || self.outbox.borrow().iter().any(|burst| !burst.is_empty())
+        {
+            glommio::executor().yield_now().await;
+        }
+    }
+
+    /// Sends the processed messages of the current burst.
+    async fn flush(&self) {
+        let bursts: Vec<_> = self
+            .outbox
+            .borrow_mut()
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, burst)| !burst.is_empty())
+            .map(|(next_shard, burst)| (next_shard, std::mem::take(burst)))
+            .collect();
+        for (next_shard, burst) in bursts {
+            self.send(next_shard, burst).await;
+        }
+    }
+
+    /// Sends `burst` to the shard with id `shard`. The run is aborted if the
+    /// channel is closed, since the messages can not go on.
+    async fn send(&self, shard: usize, burst: Burst<MsgData>) {
+        if let Err(error) = self.shard.send_to(shard, burst).await {
+            self.failures.fail(error);
+        }
+    }
+
+    /// Performs a stage in the message pipeline before sending it on to the
+    /// next shard with the rest of the burst. Async stages need a permit
+    /// from `concurrency` to start. Without one, the message is parked until
+    /// `start_parked` gets one for it, so that the worker continues with the
+    /// other messages.
+    fn worker_function(&self, message: ChannelElement<MsgData>) {
+        let index = message.pipeline_index;
+        // Assume that there is work in the pipeline
+        let is_async =
+            matches!(self.stages.borrow()[index], StageInstance::Async(_));
+        let permit = match is_async {
+            true => {
+                // The parked messages get the permits first
+                let permit = match self.parked.borrow().is_empty() {
+                    true => self.concurrency.try_acquire_static_permit(1).ok(),
+                    false => None,
+                };
+                let Some(permit) = permit else {
+                    self.parked.borrow_mut().push_back(message);
+                    self.nr_parked.signal(1);
+                    return;
+                };
+                Some(permit)
+            }
+            false => None,
+        };
+        self.run_stage(message, permit);
+    }
+
+    /// Runs the stage of `message`. Async stages run in their own task,
+    /// holding `permit`, and their messages are sent on alone once they are
+    /// done. Panics of the stage are caught, and the message is discarded.
+    fn run_stage(
+        &self,
+        mut message: ChannelElement<MsgData>,
+        permit: Option<StaticPermit>,
+    ) {
+        let index = message.pipeline_index;
+        let start = Instant::now();
+        let processed = {
+            let mut context = self.context.borrow_mut();
+            context.set_stage_index(index);
+            match &mut self.stages.borrow_mut()[index] {
+                StageInstance::Sync(stage) => {
+                    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                        stage.process(&mut message, &mut context)
+                    }));
+                    Either::Left((message, outcome))
+                }
+                StageInstance::Async(stage) => {
+                    let task = self.next_task.get();
+                    self.next_task.set(task + 1);
+                    self.in_async
+                        .borrow_mut()
+                        .insert(task, LostMessage::of(&message));
+                    let future: LocalBoxFuture<_> =
+                        match panic::catch_unwind(AssertUnwindSafe(|| {
+                            stage.process(message, &mut context)
+                        })) {
+                            Ok(future) => AssertUnwindSafe(future)
+                                .catch_unwind()
+                                .boxed_local(),
+                            Err(payload) => ready(Err(payload)).boxed_local(),
+                        };
+                    Either::Right((future, task))
+                }
+            }
+        };
+        self.add_busy(index, start.elapsed());
+
+        match processed {
+            Either::Left((message, outcome)) => {
+                let outcome = outcome
+                    .unwrap_or_else(|payload| self.panicked(index, payload));
+                let (next_shard, message) = self.send_on(message, outcome);
+                self.outbox.borrow_mut()[next_shard].push(message);
+            }
+            Either::Right((future, task)) => {
+                let handler = self.clone();
+                glommio::executor()
+                    .spawn_local(async move {
+                        let processed = future.await;
+                        drop(permit);
+                        // The worker has given up on the message if the drain
+                        // timed out
+                        let Some(lost) =
+                            handler.in_async.borrow_mut().remove(&task)
+                        else {
+                            return;
+                        };
+                        match processed {
+                            Ok((message, outcome)) => {
+                                let (next_shard, message) =
+                                    handler.send_on(message, outcome);
+                                handler.send(next_shard, vec![message]).await;
+                            }
+                            Err(payload) => {
+                                handler.panicked(index, payload);
+                                handler.lose(lost).await;
+                            }
+                        }
+                    })
+                    .detach();
+            }
+        }
+    }
+
+    /// Counts that the stage with index `stage` panicked with `payload`.
+    /// Returns what the message is discarded as.
+    fn panicked(
+        &self,
+        stage: usize,
+        payload: Box<dyn Any + Send>,
+    ) -> StageOutcome {
+        let mut worker_stats = self.worker_stats.take();
+        worker_stats.panics[stage] += 1;
+        self.worker_stats.set(worker_stats);
+
+        let worker_id = self.context.borrow().worker_id();
+        self.failures
+            .panicked(StagePanic::new(stage, worker_id, payload));
+        PANIC_OUTCOME
+    }
+
+    /// Counts that this worker is done with a message that was lost or
+    /// abandoned in an async stage, and lets the controller know that it has
+    /// left the pipeline.
+    async fn lose(&self, lost: LostMessage) {
+        self.load.done(self.context.borrow().worker_id());
+        if let FlowSlot::Owned(slot) = lost.flow_slot {
+            self.flows.done(slot);
+        }
+        self.failures.lose(lost);
+        // The empty burst wakes up the controller to count it
+        self.send(DATA_MESH_CONTROLLER_ID, Vec::new()).await;
+    }
+
+    /// Moves `message` on after the stage decided `outcome`. Returns the
+    /// shard to send it to.
+    fn send_on(
+        &self,
+        mut message: ChannelElement<MsgData>,
+        outcome: StageOutcome,
+    ) -> (usize, ChannelElement<MsgData>) {
+        self.done(&mut message);
+        self.pipeline.advance(&mut message, outcome);
+
+        let next_shard = self.next_shard(&mut message);
+        (next_shard, message)
+    }
+
+    /// Adds the time spent running the stage with index `stage` on a message
+    fn add_busy(&self, stage: usize, busy: Duration) {
+        let mut worker_stats = self.worker_stats.take();
+        worker_stats.processed += 1;
+        worker_stats.stages[stage] += 1;
+        worker_stats.busy += busy;
+        self.worker_stats.set(worker_stats);
+    }
+
+    /// Gets the shard that will get `message` after this stage. Messages that
+    /// are done, discarded or fan out, and messages entering or leaving an
+    /// ordered stage, must pass the controller. It counts and copies them, and
+    /// sequences and reorders the messages of the ordered stages. Otherwise,
+    /// with DSW, the message goes directly to the worker of the next stage.
+    fn next_shard(&self, message: &mut ChannelElement<MsgData>) -> usize {
+        match self.pipeline.stage(message.pipeline_index) {
+            Some(next_stage)
+                if self.scheduling_type == SchedulingType::Dsw
+                    && message.discarded.is_none()
+                    && message.fan_out.is_none()
+                    && message.ordered_stage.is_none()
+                    && next_stage.queue_type != QueueType::Ordered =>
+            {
+                self.dispatcher.next_shard(message, next_stage.queue_type)
+            }
+            _ => DATA_MESH_CONTROLLER_ID,
+        }
     }
 }
 
-/// Finds the CPUs provided in `worker_cpus`
-fn get_cpuset(worker_cpus: &[u16]) -> (usize, Vec<CpuSet>) {
+/// Finds the CPUs provided in `worker_cpus`. Fails if a CPU is not available.
+pub(crate) fn get_cpuset(worker_cpus: &[u16]) -> Result<Vec<CpuSet>, Error> {
     // Finds the specific CPUs
-    let mut cpu_vec = Vec::new();
-    for cpu in worker_cpus {
-        cpu_vec
-            .push(CpuSet::online().unwrap().filter(|l| l.cpu == *cpu as usize))
-    }
+    let cpu_vec = worker_cpus
+        .iter()
+        .map(|&cpu| {
+            CpuSet::online()
+                .ok()
+                .map(|online| online.filter(|l| l.cpu == cpu as usize))
+                .filter(|set| !set.is_empty())
+                .ok_or(Error::CoreUnavailable(cpu))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(cpu_vec)
+}
 
-    assert! {cpu_vec.iter().all(|set| !set.is_empty())};
-    assert_eq!(worker_cpus.len(), cpu_vec.len());
+/// The threads of the workers, and the meshes to join to reach them
+type Workers<MsgData> = (
+    glommio::PoolThreadHandles<Result<WorkerReport, Error>>,
+    DataMesh<MsgData>,
+    ControlMesh,
+);
 
-    (cpu_vec.len(), cpu_vec)
+/// The results of a worker
+pub(crate) struct WorkerReport {
+    pub(crate) worker_id: usize,
+    pub(crate) stats: Stats,
+    pub(crate) worker_stats: WorkerStats,
 }
 
 /// Joins the shard mesh and sends and receives the required messages to the
-/// controller
+/// controller. Returns the statistics recorded by the stages and the worker,
+/// or why the worker could not take part in the run.
 async fn worker_main<MsgData: Send + Clone>(
     scheduling_type: SchedulingType,
     control_mesh: &ControlMesh,
-    nr_cores: usize,
     data_mesh: &DataMesh<MsgData>,
-    stop_time: Instant,
-) {
-    let (control_sender, control_receiver) =
-        control_mesh.clone().join().await.unwrap();
-
-    let handler = RequestHandler::new(scheduling_type, nr_cores + 1, stop_time);
-
-    // ignore the shard function
-    let mut shard = Sharded::new(data_mesh.clone(), |_, _| 0, handler.clone())
+    pipeline: Arc<Pipeline<MsgData>>,
+    dispatcher: Dispatcher,
+    failures: Arc<Failures>,
+) -> Result<WorkerReport, Error> {
+    let (control_sender, control_receiver) = control_mesh
+        .clone()
+        .join()
         .await
-        .unwrap();
+        .map_err(|error| Error::MeshJoinFailed(error.to_string()))?;
+
+    let (shard, receivers) = MeshShard::join(data_mesh.clone()).await?;
+    let worker_id = shard.shard_id() - 1;
+    let handler = RequestHandler::new(
+        scheduling_type,
+        pipeline,
+        dispatcher,
+        failures,
+        shard,
+    );
+    handler.set_stages(worker_id);
+    let consumers = receivers.handle_with(handler.clone());
 
-    handler.set_shard(&mut shard);
+    let processing =
+        glommio::executor().spawn_local(enclose!((handler) async move {
+            handler.process_queue().await
+        }));
+    let parking =
+        glommio::executor().spawn_local(enclose!((handler) async move {
+            handler.start_parked().await
+        }));
+    let balancing =
+        (handler.scheduling_type == SchedulingType::Dsw).then(|| {
+            glommio::executor().spawn_local(enclose!((handler) async move {
+                handler.balance_flows().await
+            }))
+        });
+    let stealing = handler.pipeline.work_stealing().then(|| {
+        glommio::executor().spawn_local(enclose!((handler) async move {
+            handler.steal_work().await
+        }))
+    });
 
     // Send to the mesh that this shard has initialized and will wait for the
     // signal to close
@@ -169,42 +755,76 @@ async fn worker_main<MsgData: Send + Clone>(
             ControlMessage::WorkerInitializationComplete,
         )
         .await
-        .unwrap();
+        .map_err(|_| Error::ChannelClosed)?;
 
     // Wait for the controller to determine that execution is done
-    control_receiver
-        .recv_from(CONTROL_MESH_CONTROLLER_ID)
-        .await
-        .unwrap()
-        .unwrap();
+    loop {
+        let message = control_receiver
+            .recv_from(CONTROL_MESH_CONTROLLER_ID)
+            .await
+            .ok()
+            .flatten()
+            .ok_or(Error::ChannelClosed)?;
+        match message {
+            ControlMessage::StopIngress => handler.ingress.set(false),
+            ControlMessage::Drain => {
+                handler.drain().await;
+                control_sender
+                    .send_to(
+                        CONTROL_MESH_CONTROLLER_ID,
+                        ControlMessage::DrainComplete,
+                    )
+                    .await
+                    .map_err(|_| Error::ChannelClosed)?;
+            }
+            ControlMessage::Shutdown => break,
+            message => return Err(Error::UnexpectedControlMessage(message)),
+        }
+    }
 
-    shard.close().await;
+    handler.shard.close()?;
+    consumers.join().await;
+    handler.waiting.close();
+    processing.await;
+    handler.nr_parked.close();
+    parking.await;
+    handler.running.set(false);
+    for task in balancing.into_iter().chain(stealing) {
+        task.await;
+    }
+
+    let stats = handler.context.borrow_mut().take_stats();
+    Ok(WorkerReport {
+        worker_id,
+        stats,
+        worker_stats: handler.worker_stats.take(),
+    })
 }
 
-/// Spawns workers in a mesh for the CPUs specified in `worker_cores`
+/// Spawns a worker in a mesh for every executor of `placement`. Fails if the
+/// workers could not be started.
 pub fn spawn_workers<MsgData: Send + Clone>(
     scheduling_type: SchedulingType,
-    worker_cores: &[u16],
-    stop_time: Instant,
-) -> (
-    glommio::PoolThreadHandles<()>,
-    DataMesh<MsgData>,
-    ControlMesh,
-) {
-    let (nr_cores, cpu_vec) = get_cpuset(worker_cores);
+    placement: PoolPlacement,
+    pipeline: Arc<Pipeline<MsgData>>,
+    dispatcher: Dispatcher,
+    failures: Arc<Failures>,
+) -> Result<Workers<MsgData>, Error> {
+    let nr_cores = placement.executor_count();
 
-    // Sends the regular messages
-    let data_mesh =
-        MeshBuilder::full(nr_cores + 1, MESH_CHANNEL_SIZE / nr_cores);
+    // Sends the regular messages, in bursts
+    let channel_size =
+        (MESH_CHANNEL_SIZE / nr_cores / pipeline.burst_size()).max(1);
+    let data_mesh = MeshBuilder::full(nr_cores + 1, channel_size);
     // Used for sending messages about the execution
     let control_mesh = MeshBuilder::full(nr_cores + 1, 1);
 
-    let pool = LocalExecutorPoolBuilder::new(PoolPlacement::Custom(cpu_vec))
+    let pool = LocalExecutorPoolBuilder::new(placement)
         .name("Workers")
         .on_all_shards(enclose!((data_mesh, control_mesh) move || async move {
-            worker_main(scheduling_type, &control_mesh, nr_cores, &data_mesh, stop_time).await;
+            worker_main(scheduling_type, &control_mesh, &data_mesh, pipeline, dispatcher.clone(), failures.clone()).await
         }))
-        .unwrap();
+        .map_err(|error| Error::ExecutorFailed(error.to_string()))?;
 
-    (pool, data_mesh, control_mesh)
+    Ok((pool, data_mesh, control_mesh))
 }